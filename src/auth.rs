@@ -0,0 +1,353 @@
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rusqlite::{Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use time::OffsetDateTime;
+
+use crate::{error::error_response, stats::format_date, AppState};
+
+/// Prefix of every token, to make them recognisable, e.g. to secret scanners.
+const TOKEN_PREFIX: &str = "ppc_";
+
+const USAGE: &str = "\
+Usage: pixelpagecount [OPTIONS] tokens <COMMAND>
+
+Commands:
+  list                                        List tokens (not the tokens themselves,
+                                              which are only stored hashed)
+  mint [--domain <DOMAIN>] [--admin] [--name <NAME>]
+                                              Create a token and print it; without --domain
+                                              it grants access to all domains, without
+                                              --admin it is read-only
+  revoke <ID>                                 Revoke a token";
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    /// No token: read stats of public sites only.
+    Public,
+    /// Read stats.
+    Read,
+    /// Read stats and manage sites.
+    Admin,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Public => "public",
+            Scope::Read   => "read",
+            Scope::Admin  => "admin",
+        }
+    }
+}
+
+/// What the bearer of the request's token may access.
+///
+/// The token is taken from `Authorization: Bearer <token>` or from the password of
+/// HTTP basic authentication, which lets browsers open the dashboard. Extracting
+/// this from a request rejects it with `401 Unauthorized` if it carries an invalid
+/// token. Requests without a token get `Scope::Public` access to the sites marked
+/// as public. Share links set the access for their routes in the request extensions.
+#[derive(Clone)]
+pub struct Access {
+    /// The only domain the token grants access to, or `None` for all domains.
+    pub domain: Option<String>,
+    pub scope:  Scope,
+    /// For `Scope::Public`, the domains whose stats anyone may read.
+    pub public_domains: Vec<String>,
+}
+
+/// Why a token does not grant access to what was requested.
+pub struct Denied {
+    message: String,
+    /// No token was given, so asking for one may help.
+    anonymous: bool,
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl IntoResponse for Denied {
+    fn into_response(self) -> Response {
+        if self.anonymous {
+            unauthorized(&self.message)
+        } else {
+            error_response(StatusCode::FORBIDDEN, self.message)
+        }
+    }
+}
+
+impl Access {
+    /// Checks that the token grants access to `domain`, or, if no domain was
+    /// requested, limits the request to the token's domain.
+    pub fn restrict(&self, domain: &mut Option<String>) -> Result<(), Denied> {
+        if self.scope == Scope::Public {
+            return match (self.public_domains.as_slice(), domain.as_deref()) {
+                (_, Some(requested)) if self.is_public(requested) => Ok(()),
+                ([only], None) => {
+                    *domain = Some(only.clone());
+                    Ok(())
+                }
+                _ => Err(self.deny("API token required")),
+            };
+        }
+        match (&self.domain, domain.as_deref()) {
+            (None, _) => Ok(()),
+            (Some(allowed), None) => {
+                *domain = Some(allowed.clone());
+                Ok(())
+            }
+            (Some(allowed), Some(requested)) if allowed.eq_ignore_ascii_case(requested) => Ok(()),
+            (Some(_), Some(requested)) => Err(self.deny(format!("token does not grant access to {requested}"))),
+        }
+    }
+
+    /// Checks that the token grants admin access to `domain`, or to all domains if `None`.
+    pub fn require_admin(&self, domain: Option<&str>) -> Result<(), Denied> {
+        if self.scope < Scope::Admin {
+            return Err(self.deny("admin token required"));
+        }
+        match (&self.domain, domain) {
+            (None, _) => Ok(()),
+            (Some(allowed), Some(requested)) if allowed.eq_ignore_ascii_case(requested) => Ok(()),
+            _ => Err(self.deny("token does not grant admin access to this domain")),
+        }
+    }
+
+    pub fn is_public(&self, domain: &str) -> bool {
+        self.public_domains.iter().any(|d| d.eq_ignore_ascii_case(domain))
+    }
+
+    pub fn deny(&self, message: impl Into<String>) -> Denied {
+        Denied { message: message.into(), anonymous: self.scope == Scope::Public }
+    }
+}
+
+impl FromRequestParts<AppState> for Access {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Access, Response> {
+        if let Some(access) = parts.extensions.get::<Access>() {
+            return Ok(access.clone());
+        }
+
+        let Some(header) = parts.headers.get(header::AUTHORIZATION) else {
+            return Ok(Access { domain: None, scope: Scope::Public, public_domains: state.sites.public_domains() });
+        };
+        let token = header.to_str().ok()
+            .and_then(token_from_header)
+            .ok_or_else(|| unauthorized("malformed Authorization header"))?;
+
+        let token_hash = hash(&token);
+        let access = state.reads.run(move |db| {
+            db.query_row(
+                "SELECT domain, scope FROM tokens WHERE token_hash = ? AND revoked IS NULL",
+                [token_hash],
+                |row| Ok((row.get::<_, Option<String>>(0)?, row.get::<_, String>(1)?)),
+            ).optional()
+        }).await;
+
+        match access {
+            Ok(Some((domain, scope))) => Ok(Access {
+                domain,
+                scope: if scope == "admin" { Scope::Admin } else { Scope::Read },
+                public_domains: Vec::new(),
+            }),
+            Ok(None) => Err(unauthorized("invalid API token")),
+            Err(e) => {
+                tracing::error!(error = %e, "cannot look up API token");
+                Err(error_response(StatusCode::INTERNAL_SERVER_ERROR, "cannot check API token"))
+            }
+        }
+    }
+}
+
+fn token_from_header(value: &str) -> Option<String> {
+    let (scheme, credentials) = value.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(credentials.trim().to_string())
+    } else if scheme.eq_ignore_ascii_case("basic") {
+        // The user name is ignored; the token is the password
+        let decoded = String::from_utf8(STANDARD.decode(credentials.trim()).ok()?).ok()?;
+        decoded.split_once(':').map(|(_, password)| password.to_string())
+    } else {
+        None
+    }
+}
+
+fn unauthorized(message: &str) -> Response {
+    let mut response = error_response(StatusCode::UNAUTHORIZED, message);
+    response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        header::HeaderValue::from_static("Basic realm=\"pixelpagecount\", charset=\"UTF-8\""),
+    );
+    response
+}
+
+pub fn hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Generates a random, unguessable token starting with `prefix`.
+pub fn generate(prefix: &str) -> Result<String, String> {
+    let mut bytes = [0u8; 32];
+    getrandom::fill(&mut bytes).map_err(|e| format!("cannot generate token: {e}"))?;
+    Ok(format!("{prefix}{}", bytes.iter().map(|b| format!("{b:02x}")).collect::<String>()))
+}
+
+/// Runs a `tokens` subcommand.
+pub fn run_command(db: &Connection, args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["list"] => {
+            let mut stmt = db.prepare(
+                "SELECT id, name, domain, scope, created, revoked FROM tokens ORDER BY id"
+            ).map_err(|e| e.to_string())?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                ))
+            }).map_err(|e| e.to_string())?;
+            for row in rows {
+                let (id, name, domain, scope, created, revoked) = row.map_err(|e| e.to_string())?;
+                let domain = domain.unwrap_or_else(|| "all domains".into());
+                let revoked = revoked.map(|r| format!(", revoked {r}")).unwrap_or_default();
+                println!("{id}  {name}  {scope} on {domain}  (created {created}{revoked})");
+            }
+            Ok(())
+        }
+        ["mint", flags @ ..] => {
+            let mut domain = None;
+            let mut scope = Scope::Read;
+            let mut name = String::new();
+            let mut flags = flags.iter();
+            while let Some(flag) = flags.next() {
+                match *flag {
+                    "--domain" => domain = Some(flags.next().ok_or("missing value for --domain")?.to_ascii_lowercase()),
+                    "--name"   => name = flags.next().ok_or("missing value for --name")?.to_string(),
+                    "--admin"  => scope = Scope::Admin,
+                    other => return Err(format!("unexpected argument {other}\n\n{USAGE}")),
+                }
+            }
+
+            let token = generate(TOKEN_PREFIX)?;
+            db.execute(
+                "INSERT INTO tokens (name, token_hash, domain, scope, created) VALUES (?, ?, ?, ?, ?)",
+                (&name, hash(&token), &domain, scope.as_str(), format_date(OffsetDateTime::now_utc().date())),
+            ).map_err(|e| e.to_string())?;
+            eprintln!(
+                "Created {} token {} for {}. It is shown only once:",
+                scope.as_str(),
+                db.last_insert_rowid(),
+                domain.as_deref().unwrap_or("all domains"),
+            );
+            println!("{token}");
+            Ok(())
+        }
+        ["revoke", id] => {
+            let id: i64 = id.parse().map_err(|_| format!("invalid token id '{id}'"))?;
+            let revoked = db.execute(
+                "UPDATE tokens SET revoked = ? WHERE id = ? AND revoked IS NULL",
+                (format_date(OffsetDateTime::now_utc().date()), id),
+            ).map_err(|e| e.to_string())?;
+            if revoked == 0 {
+                return Err(format!("no active token with id {id}"));
+            }
+            println!("Revoked token {id}");
+            Ok(())
+        }
+        ["help"] => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        _ => Err(USAGE.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(scope: Scope, domain: Option<&str>, public_domains: &[&str]) -> Access {
+        Access {
+            domain: domain.map(str::to_string),
+            scope,
+            public_domains: public_domains.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+
+    /// The domain `access` restricts a request for `requested` to, or `None` if denied.
+    fn restrict(access: &Access, requested: Option<&str>) -> Option<Option<String>> {
+        let mut domain = requested.map(str::to_string);
+        access.restrict(&mut domain).ok().map(|()| domain)
+    }
+
+    #[test]
+    fn public_access_only_reads_public_sites() {
+        let one = access(Scope::Public, None, &["example.com"]);
+        assert_eq!(restrict(&one, Some("Example.COM")), Some(Some("Example.COM".into())));
+        assert_eq!(restrict(&one, None), Some(Some("example.com".into())));
+        assert_eq!(restrict(&one, Some("other.test")), None);
+
+        let two = access(Scope::Public, None, &["example.com", "blog.test"]);
+        assert_eq!(restrict(&two, None), None);
+        assert_eq!(restrict(&two, Some("blog.test")), Some(Some("blog.test".into())));
+        assert_eq!(restrict(&access(Scope::Public, None, &[]), None), None);
+        assert!(two.deny("no").anonymous);
+    }
+
+    #[test]
+    fn tokens_for_all_domains_read_any() {
+        let all = access(Scope::Read, None, &[]);
+        assert_eq!(restrict(&all, None), Some(None));
+        assert_eq!(restrict(&all, Some("other.test")), Some(Some("other.test".into())));
+    }
+
+    #[test]
+    fn domain_tokens_are_limited_to_their_domain() {
+        let one = access(Scope::Read, Some("example.com"), &[]);
+        assert_eq!(restrict(&one, None), Some(Some("example.com".into())));
+        assert_eq!(restrict(&one, Some("EXAMPLE.com")), Some(Some("EXAMPLE.com".into())));
+        assert_eq!(restrict(&one, Some("other.test")), None);
+        assert!(!one.deny("no").anonymous);
+    }
+
+    #[test]
+    fn admin_requires_admin_scope_for_the_domain() {
+        assert!(access(Scope::Read, None, &[]).require_admin(None).is_err());
+        assert!(access(Scope::Public, None, &["example.com"]).require_admin(Some("example.com")).is_err());
+        assert!(access(Scope::Admin, None, &[]).require_admin(None).is_ok());
+        assert!(access(Scope::Admin, None, &[]).require_admin(Some("example.com")).is_ok());
+
+        let one = access(Scope::Admin, Some("example.com"), &[]);
+        assert!(one.require_admin(Some("Example.com")).is_ok());
+        assert!(one.require_admin(Some("other.test")).is_err());
+        assert!(one.require_admin(None).is_err());
+    }
+
+    #[test]
+    fn token_from_bearer_and_basic_headers() {
+        assert_eq!(token_from_header("Bearer ppc_abc"), Some("ppc_abc".into()));
+        assert_eq!(token_from_header("bearer  ppc_abc "), Some("ppc_abc".into()));
+        let basic = format!("Basic {}", STANDARD.encode("anyone:ppc_abc"));
+        assert_eq!(token_from_header(&basic), Some("ppc_abc".into()));
+
+        assert_eq!(token_from_header("ppc_abc"), None);
+        assert_eq!(token_from_header("Token ppc_abc"), None);
+        assert_eq!(token_from_header("Basic not-base64!"), None);
+        assert_eq!(token_from_header(&format!("Basic {}", STANDARD.encode("no-colon"))), None);
+    }
+}
@@ -0,0 +1,209 @@
+use axum::{
+    extract::{ConnectInfo, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use std::net::SocketAddr;
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    auth::Access,
+    dashboard::escape,
+    error::{AppError, AppResult},
+    extract::Query,
+    hits,
+    stats::format_date,
+    AppState,
+};
+
+const DEFAULT_LABEL: &str = "views";
+const DEFAULT_COLOR: &str = "#007ec6";
+const LABEL_COLOR: &str = "#555";
+/// Longest label accepted, in characters.
+const MAX_LABEL: usize = 40;
+/// Shown instead of the count to those who may not read the site's stats.
+const HIDDEN_COUNT: &str = "n/a";
+
+/// Named colours, as used by shields.io.
+const NAMED_COLORS: &[(&str, &str)] = &[
+    ("brightgreen", "#4c1"),
+    ("green", "#97ca00"),
+    ("yellowgreen", "#a4a61d"),
+    ("yellow", "#dfb317"),
+    ("orange", "#fe7d37"),
+    ("red", "#e05d44"),
+    ("blue", "#007ec6"),
+    ("lightgrey", "#9f9f9f"),
+    ("grey", "#555"),
+];
+
+/// Query string accepted by `/badge.svg`.
+#[derive(serde::Deserialize)]
+pub struct BadgeParams {
+    domain: Option<String>,
+    page:   Option<String>,
+    label:  Option<String>,
+    color:  Option<String>,
+    /// Which views to show: `total` (default), `today` or `30d`
+    count:  Option<String>,
+}
+
+/// Counts a view like `/counter.gif`, and returns a badge showing the page's views.
+///
+/// The count is only shown for public sites, or to requests whose token or share
+/// link grants access to the site's stats; others get a placeholder.
+pub async fn badge(
+    State(state):      State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    access:            Result<Access, Response>,
+    headers:           HeaderMap,
+    Query(params):     Query<BadgeParams>,
+) -> AppResult {
+    let label = params.label.unwrap_or_else(|| DEFAULT_LABEL.into());
+    if label.chars().count() > MAX_LABEL {
+        return Err(AppError::bad_request(format!("label can be at most {MAX_LABEL} characters")));
+    }
+    let color = params.color.as_deref().map(parse_color).transpose()
+        .map_err(AppError::BadRequest)?.unwrap_or_else(|| DEFAULT_COLOR.into());
+    let today = OffsetDateTime::now_utc().date();
+    let since = match params.count.as_deref() {
+        None | Some("total") => None,
+        Some("today") => Some(today),
+        Some("30d") => Some(today - Duration::days(29)),
+        Some(other) => return Err(AppError::bad_request(format!("invalid count '{other}', expected total, today or 30d"))),
+    };
+
+    let hit = hits::Hit {
+        domain:   params.domain,
+        page:     params.page,
+        referrer: None,
+        headers:  &headers,
+        peer,
+    };
+    let count = match hits::record(&state, hit).await {
+        Some((domain, page)) if may_read(access, &domain) => {
+            let since = since.map(format_date).unwrap_or_default();
+            let views = tokio::task::spawn_blocking(move || views(&state, &domain, &page, &since)).await
+                .map_err(|e| AppError::Internal(format!("cannot read views for badge: {e}")))?;
+            format_count(views.max(0) as u64)
+        }
+        _ => HIDDEN_COUNT.to_string(),
+    };
+
+    Ok((
+        [
+            ("Content-Type", "image/svg+xml"),
+            // Image proxies such as GitHub's would otherwise show a stale count
+            ("Cache-Control", "no-cache, no-store, must-revalidate"),
+        ],
+        render(&label, &count, &color),
+    ).into_response())
+}
+
+/// Views of `page` on or after `since`, including those not written yet. Read on
+/// the writer connection, which `Writer` holds while it moves buffered views into
+/// the table, so that none are counted twice or missed.
+fn views(state: &AppState, domain: &str, page: &str, since: &str) -> i64 {
+    let db = state.db.lock().unwrap_or_else(|e| e.into_inner());
+    let written = db.query_row(
+        "SELECT COALESCE(SUM(view_count), 0) FROM pageviews WHERE domain = ? AND page = ? AND date >= ?",
+        (domain, page, since),
+        |row| row.get::<_, i64>(0),
+    ).unwrap_or_else(|e| {
+        tracing::error!(error = %e, "cannot read views for badge");
+        0
+    });
+    written + state.writer.pending_views(domain, page, since)
+}
+
+/// Whether `access` lets the request read the stats of `domain`. An invalid
+/// token only hides the count, as image requests cannot show errors.
+fn may_read(access: Result<Access, Response>, domain: &str) -> bool {
+    access.is_ok_and(|access| access.restrict(&mut Some(domain.to_string())).is_ok())
+}
+
+/// Accepts a shields.io colour name or a hex colour, with or without `#`.
+fn parse_color(color: &str) -> Result<String, String> {
+    if let Some((_, hex)) = NAMED_COLORS.iter().find(|(name, _)| *name == color) {
+        return Ok(hex.to_string());
+    }
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if matches!(hex.len(), 3 | 6) && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(format!("#{hex}"))
+    } else {
+        Err(format!("invalid color '{color}', expected a colour name or hex code"))
+    }
+}
+
+/// Formats `n` with thousands separators, e.g. `1,234`.
+fn format_count(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Approximate width of `text` in 11px Verdana, the font used for the badge.
+fn text_width(text: &str) -> f64 {
+    text.chars().map(|c| match c {
+        'i' | 'j' | 'l' | '.' | ',' | ':' | ';' | '\'' | '|' | '!' | ' ' => 3.5,
+        'f' | 'r' | 't' | 'I' | '(' | ')' | '[' | ']' | '-' | '/' => 4.5,
+        'm' | 'w' | 'M' | 'W' => 10.0,
+        'A'..='Z' => 7.5,
+        _ => 7.0,
+    }).sum()
+}
+
+fn render(label: &str, value: &str, color: &str) -> String {
+    let label_width = (text_width(label) + 12.0).round();
+    let value_width = (text_width(value) + 12.0).round();
+    let width = label_width + value_width;
+    let (label, value) = (escape(label), escape(value));
+
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {value}"><title>{label}: {value}</title><linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient><clipPath id="r"><rect width="{width}" height="20" rx="3" fill="#fff"/></clipPath><g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="{LABEL_COLOR}"/><rect x="{label_width}" width="{value_width}" height="20" fill="{color}"/><rect width="{width}" height="20" fill="url(#s)"/></g><g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11"><text x="{label_x}" y="15" fill="#010101" fill-opacity=".3">{label}</text><text x="{label_x}" y="14">{label}</text><text x="{value_x}" y="15" fill="#010101" fill-opacity=".3">{value}</text><text x="{value_x}" y="14">{value}</text></g></svg>"##,
+        label_x = label_width / 2.0,
+        value_x = label_width + value_width / 2.0,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Scope;
+    use axum::http::StatusCode;
+
+    fn access(scope: Scope, domain: Option<&str>, public_domains: &[&str]) -> Access {
+        Access {
+            domain: domain.map(str::to_string),
+            scope,
+            public_domains: public_domains.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn counts_are_hidden_unless_readable() {
+        assert!(may_read(Ok(access(Scope::Public, None, &["example.com"])), "example.com"));
+        assert!(!may_read(Ok(access(Scope::Public, None, &["example.com"])), "private.test"));
+        assert!(!may_read(Ok(access(Scope::Public, None, &[])), "private.test"));
+        assert!(may_read(Ok(access(Scope::Read, None, &[])), "private.test"));
+        assert!(may_read(Ok(access(Scope::Read, Some("private.test"), &[])), "private.test"));
+        assert!(!may_read(Ok(access(Scope::Read, Some("example.com"), &[])), "private.test"));
+        assert!(!may_read(Err(StatusCode::UNAUTHORIZED.into_response()), "example.com"));
+    }
+
+    #[test]
+    fn formats_counts_and_colors() {
+        assert_eq!(format_count(0), "0");
+        assert_eq!(format_count(1234567), "1,234,567");
+        assert_eq!(parse_color("green").as_deref(), Ok("#97ca00"));
+        assert_eq!(parse_color("ABC").as_deref(), Ok("#ABC"));
+        assert!(parse_color("#12345").is_err());
+        assert!(render("views", HIDDEN_COUNT, DEFAULT_COLOR).contains("views: n/a"));
+    }
+}
@@ -0,0 +1,114 @@
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::net::SocketAddr;
+
+use crate::{
+    error::{AppError, AppResult},
+    hits, referrer, AppState, Params,
+};
+
+/// Largest body accepted by `POST /api/hit`; a hit is a few short strings.
+pub const MAX_BODY: usize = 4 * 1024;
+
+/// How long browsers may cache the answer to a preflight request, in seconds.
+const PREFLIGHT_MAX_AGE: &str = "86400";
+
+/// `POST /api/hit`: records a page view like `/counter.gif`, from a JSON body with
+/// the same `domain`, `page` and `ref` fields as its query string. Answers
+/// `202 Accepted`, as the view is written to the database shortly after.
+///
+/// The body may be sent as `application/json` or as `text/plain`, which is what
+/// `navigator.sendBeacon` uses for strings and needs no CORS preflight.
+pub async fn hit(
+    State(state):      State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers:           HeaderMap,
+    body:              Bytes,
+) -> Response {
+    let origin = allowed_origin(&state, &headers);
+    let response = record(state, peer, headers, &body).await.into_response();
+    with_cors(response, origin)
+}
+
+async fn record(state: AppState, peer: SocketAddr, headers: HeaderMap, body: &[u8]) -> AppResult {
+    check_media_type(&headers)?;
+    let params: Params = serde_json::from_slice(body)
+        .map_err(|e| AppError::bad_request(format!("invalid hit: {e}")))?;
+
+    hits::contain("hit", async move {
+        hits::record(&state, hits::Hit {
+            domain:   params.domain,
+            page:     params.page,
+            referrer: params.referrer.as_deref(),
+            headers:  &headers,
+            peer,
+        }).await;
+    }).await;
+    Ok(StatusCode::ACCEPTED.into_response())
+}
+
+/// Checks that a beacon body is sent as `application/json` or `text/plain`, or
+/// without a content type.
+pub fn check_media_type(headers: &HeaderMap) -> AppResult<()> {
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|h| h.to_str().ok());
+    let media_type = content_type.map(|t| t.split(';').next().unwrap_or("").trim().to_ascii_lowercase());
+    if !matches!(media_type.as_deref(), None | Some("application/json" | "text/plain")) {
+        return Err(AppError::UnsupportedMediaType("expected application/json or text/plain".into()));
+    }
+    Ok(())
+}
+
+/// `OPTIONS /api/hit` and `OPTIONS /event`: answers CORS preflight requests, sent
+/// by browsers before posting JSON from another origin.
+pub async fn preflight(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let origin = allowed_origin(&state, &headers);
+    let mut response = with_cors(StatusCode::NO_CONTENT.into_response(), origin.clone());
+    if origin.is_some() {
+        let headers = response.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static("POST"));
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static("Content-Type"));
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static(PREFLIGHT_MAX_AGE));
+    }
+    response
+}
+
+/// The request's `Origin`, if it is a registered site, which may read the answers
+/// of `/api/hit` and `/event`. Other origins can still send beacons, but get no
+/// CORS headers.
+pub fn allowed_origin(state: &AppState, headers: &HeaderMap) -> Option<HeaderValue> {
+    let origin = headers.get(header::ORIGIN)?;
+    let host = referrer::parse_host(origin.to_str().ok()?)?;
+    state.sites.is_registered_host(&host).then(|| origin.clone())
+}
+
+pub fn with_cors(mut response: Response, origin: Option<HeaderValue>) -> Response {
+    let headers = response.headers_mut();
+    // The answer depends on the origin, so caches must not share it between sites
+    headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+    if let Some(origin) = origin {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_json_and_text() {
+        let with_type = |content_type: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+            check_media_type(&headers)
+        };
+        assert!(check_media_type(&HeaderMap::new()).is_ok());
+        assert!(with_type("application/json").is_ok());
+        assert!(with_type("Text/Plain; charset=UTF-8").is_ok());
+        assert!(matches!(with_type("application/x-www-form-urlencoded"), Err(AppError::UnsupportedMediaType(_))));
+    }
+}
@@ -0,0 +1,99 @@
+use std::path::Path;
+
+/// Case-insensitive substrings identifying crawlers, link previewers, monitoring
+/// services, HTTP libraries and headless browsers. Checked in order, so specific
+/// names come before the generic ones at the end, which double as labels for
+/// bots not listed by name.
+const BUILTIN_PATTERNS: &[&str] = &[
+    "googlebot", "google-inspectiontool", "adsbot-google", "mediapartners-google", "feedfetcher-google",
+    "bingbot", "bingpreview", "duckduckbot", "yandexbot", "baiduspider", "applebot", "petalbot",
+    "slurp", "sogou", "exabot", "seznambot", "ahrefsbot", "semrushbot", "mj12bot", "dotbot",
+    "bytespider", "gptbot", "chatgpt-user", "claudebot", "ccbot", "perplexitybot", "amazonbot",
+    "facebookexternalhit", "facebookcatalog", "twitterbot", "linkedinbot", "slackbot", "discordbot",
+    "telegrambot", "whatsapp", "skypeuripreview", "embedly", "pinterestbot", "redditbot",
+    "lighthouse", "pingdom", "uptimerobot", "statuscake", "site24x7", "newrelicpinger",
+    "headlesschrome", "phantomjs", "puppeteer", "playwright", "selenium",
+    "curl/", "wget/", "python-requests", "python-urllib", "aiohttp", "httpx", "go-http-client",
+    "java/", "okhttp", "apache-httpclient", "libwww-perl", "node-fetch", "axios", "scrapy",
+    "crawler", "spider", "bot",
+];
+
+/// Label used when the User-Agent header is missing or empty.
+const EMPTY_USER_AGENT: &str = "(empty)";
+
+/// Classifies User-Agent strings as bots.
+pub struct BotDetector {
+    patterns: Vec<String>,
+}
+
+impl BotDetector {
+    /// Creates a detector using the built-in patterns, preceded by those in
+    /// `extra` if given: a text file with one pattern per line, where blank
+    /// lines and lines starting with `#` are ignored.
+    pub fn new(extra: Option<&Path>) -> Result<BotDetector, String> {
+        let mut patterns = Vec::new();
+        if let Some(path) = extra {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| format!("cannot read bot patterns {}: {e}", path.display()))?;
+            patterns.extend(
+                text.lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_ascii_lowercase),
+            );
+        }
+        patterns.extend(BUILTIN_PATTERNS.iter().map(|p| p.to_string()));
+        Ok(BotDetector { patterns })
+    }
+
+    /// Returns the pattern matching `user_agent`, or `None` if it looks like a
+    /// regular browser. Only this label is ever stored, never the User-Agent itself.
+    pub fn classify(&self, user_agent: Option<&str>) -> Option<&str> {
+        let user_agent = match user_agent.map(str::trim) {
+            None | Some("") => return Some(EMPTY_USER_AGENT),
+            Some(ua) => ua.to_ascii_lowercase(),
+        };
+        self.patterns.iter()
+            .find(|pattern| user_agent.contains(pattern.as_str()))
+            .map(|pattern| pattern.trim_end_matches('/'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHROME: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0 Safari/537.36";
+
+    #[test]
+    fn browsers_are_not_bots() {
+        let detector = BotDetector::new(None).unwrap();
+        assert_eq!(detector.classify(Some(CHROME)), None);
+    }
+
+    #[test]
+    fn bots_are_labelled_by_the_first_matching_pattern() {
+        let detector = BotDetector::new(None).unwrap();
+        let googlebot = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+        assert_eq!(detector.classify(Some(googlebot)), Some("googlebot"));
+        assert_eq!(detector.classify(Some("curl/8.5.0")), Some("curl"));
+        assert_eq!(detector.classify(Some("SomeNewCrawler/1.0")), Some("crawler"));
+    }
+
+    #[test]
+    fn missing_or_blank_user_agents_are_bots() {
+        let detector = BotDetector::new(None).unwrap();
+        assert_eq!(detector.classify(None), Some(EMPTY_USER_AGENT));
+        assert_eq!(detector.classify(Some("  ")), Some(EMPTY_USER_AGENT));
+    }
+
+    #[test]
+    fn extra_patterns_come_first() {
+        let path = std::env::temp_dir().join(format!("pixelpagecount-{}-bots.txt", std::process::id()));
+        std::fs::write(&path, "# monitoring\n\nMyMonitor\n").unwrap();
+        let detector = BotDetector::new(Some(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(detector.classify(Some("MyMonitor bot/1.0")), Some("mymonitor"));
+        assert_eq!(detector.classify(Some(CHROME)), None);
+    }
+}
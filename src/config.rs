@@ -0,0 +1,474 @@
+use serde::Deserialize;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const USAGE: &str = "\
+Usage: pixelpagecount [OPTIONS] [COMMAND]
+
+Commands:
+  serve              Run the HTTP server (default)
+  sites              Manage registered sites, see `pixelpagecount sites help`
+  tokens             Manage API tokens, see `pixelpagecount tokens help`
+  migrate            Upgrade the database schema, see `pixelpagecount migrate help`
+
+Options:
+  --port <PORT>      Port to listen on                 [env: PORT]        (default: 8080)
+  --bind <ADDR>      IP address to bind to             [env: PIXEL_BIND]  (default: 0.0.0.0)
+  --db <PATH>        Path to the SQLite database       [env: PIXEL_DB]    (default: /data/analytics.db if /data exists, else data/analytics.db)
+  --config <PATH>    Path to a TOML configuration file [env: PIXEL_CONFIG]
+  --require-params   Only count hits that give domain and page explicitly,
+                     instead of inferring them from the Referer header
+                                                       [env: PIXEL_REQUIRE_PARAMS]
+  --unregistered-sites <MODE>
+                     What to do with hits for domains not registered with
+                     `sites add`: count, drop or log   [env: PIXEL_UNREGISTERED_SITES] (default: count)
+  --bots <MODE>      What to do with hits from bots: count them as views,
+                     drop them, or keep them separate  [env: PIXEL_BOTS] (default: separate)
+  --bot-patterns <PATH>
+                     File with extra User-Agent patterns identifying bots,
+                     one per line                      [env: PIXEL_BOT_PATTERNS]
+  --flush-interval <MS>
+                     Milliseconds between writes of buffered hits to the
+                     database                          [env: PIXEL_FLUSH_INTERVAL] (default: 1000)
+  --flush-hits <N>   Write buffered hits as soon as this many are buffered
+                                                       [env: PIXEL_FLUSH_HITS] (default: 1000)
+  --shutdown-timeout <SECS>
+                     How long to wait for requests in progress when asked
+                     to stop by SIGTERM or Ctrl-C      [env: PIXEL_SHUTDOWN_TIMEOUT] (default: 5)
+  --log-format <FORMAT>
+                     Format of log lines: text or json [env: PIXEL_LOG_FORMAT] (default: text)
+  -h, --help         Print this help
+
+Settings are resolved in the order: command line, environment, config file, defaults.
+Log levels are set with PIXEL_LOG (or RUST_LOG), e.g. PIXEL_LOG=warn.";
+
+/// Runtime configuration, resolved from the command line, the environment,
+/// an optional TOML file and built-in defaults (in that order of precedence).
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind:    IpAddr,
+    pub port:    u16,
+    pub db_path: PathBuf,
+    /// Only count hits with explicit `domain` and `page`, never infer them from `Referer`.
+    pub require_params: bool,
+    pub unregistered: Unregistered,
+    pub bots: Bots,
+    pub bot_patterns: Option<PathBuf>,
+    /// Longest time hits are buffered in memory before being written.
+    pub flush_interval: Duration,
+    /// Number of buffered hits that triggers a write before `flush_interval` is up.
+    pub flush_hits: usize,
+    /// Longest time to wait for requests in progress when shutting down.
+    pub shutdown_timeout: Duration,
+    pub log_format: LogFormat,
+    /// Subcommand and its arguments; empty when none was given.
+    pub command: Vec<String>,
+}
+
+/// Handling of hits for domains that are not in the `sites` table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unregistered {
+    /// Count them like any other hit.
+    Count,
+    /// Silently ignore them.
+    Drop,
+    /// Ignore them, but record the domain in `unregistered_hits`.
+    Log,
+}
+
+/// Handling of hits whose User-Agent identifies a bot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bots {
+    /// Count them like any other hit.
+    Count,
+    /// Silently ignore them.
+    Drop,
+    /// Count them in `bot_hits` instead of `pageviews`.
+    Separate,
+}
+
+/// Format of the log lines written to stderr.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// One human-readable line per event.
+    Text,
+    /// One JSON object per event, for log collectors.
+    Json,
+}
+
+impl Config {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// `--help` was requested; not really an error, but stops startup.
+    Help,
+    MissingValue(String),
+    UnknownArgument(String),
+    Invalid { key: String, value: String, reason: String },
+    ReadFile { path: PathBuf, source: std::io::Error },
+    ParseFile { path: PathBuf, source: toml::de::Error },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Help => f.write_str(USAGE),
+            ConfigError::MissingValue(arg) => write!(f, "missing value for {arg}\n\n{USAGE}"),
+            ConfigError::UnknownArgument(arg) => write!(f, "unknown argument {arg}\n\n{USAGE}"),
+            ConfigError::Invalid { key, value, reason } => write!(f, "invalid {key} '{value}': {reason}"),
+            ConfigError::ReadFile { path, source } => write!(f, "cannot read config file {}: {source}", path.display()),
+            ConfigError::ParseFile { path, source } => write!(f, "cannot parse config file {}: {source}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Settings as they appear in the TOML file; every key is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<String>,
+    port: Option<u16>,
+    db:   Option<PathBuf>,
+    require_params: Option<bool>,
+    unregistered_sites: Option<String>,
+    bots: Option<String>,
+    bot_patterns: Option<PathBuf>,
+    flush_interval: Option<u64>,
+    flush_hits: Option<usize>,
+    shutdown_timeout: Option<u64>,
+    log_format: Option<String>,
+}
+
+/// Raw, unvalidated settings from one source.
+#[derive(Debug, Default)]
+struct Layer {
+    bind:   Option<String>,
+    port:   Option<String>,
+    db:     Option<PathBuf>,
+    config: Option<PathBuf>,
+    require_params: Option<String>,
+    unregistered_sites: Option<String>,
+    bots: Option<String>,
+    bot_patterns: Option<PathBuf>,
+    flush_interval: Option<String>,
+    flush_hits: Option<String>,
+    shutdown_timeout: Option<String>,
+    log_format: Option<String>,
+    command: Vec<String>,
+}
+
+impl Layer {
+    fn from_args(args: impl IntoIterator<Item = String>) -> Result<Layer, ConfigError> {
+        let mut layer = Layer::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // Everything from the first positional argument on belongs to the subcommand
+            if !arg.starts_with('-') {
+                layer.command.push(arg);
+                layer.command.extend(args);
+                break;
+            }
+            // Accept both `--port 8080` and `--port=8080`
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+                _ => (arg.clone(), None),
+            };
+            let mut value = || inline.clone().or_else(|| args.next()).ok_or_else(|| ConfigError::MissingValue(flag.clone()));
+            match flag.as_str() {
+                "-h" | "--help" => return Err(ConfigError::Help),
+                "--bind"   => layer.bind   = Some(value()?),
+                "--port"   => layer.port   = Some(value()?),
+                "--db"     => layer.db     = Some(value()?.into()),
+                "--config" => layer.config = Some(value()?.into()),
+                "--require-params" => layer.require_params = Some(inline.clone().unwrap_or_else(|| "true".into())),
+                "--unregistered-sites" => layer.unregistered_sites = Some(value()?),
+                "--bots" => layer.bots = Some(value()?),
+                "--bot-patterns" => layer.bot_patterns = Some(value()?.into()),
+                "--flush-interval" => layer.flush_interval = Some(value()?),
+                "--flush-hits" => layer.flush_hits = Some(value()?),
+                "--shutdown-timeout" => layer.shutdown_timeout = Some(value()?),
+                "--log-format" => layer.log_format = Some(value()?),
+                _ => return Err(ConfigError::UnknownArgument(arg)),
+            }
+        }
+        Ok(layer)
+    }
+
+    /// Reads settings from environment variables, looked up with `get`.
+    fn from_env(get: impl Fn(&str) -> Option<String>) -> Layer {
+        let var = |name: &str| get(name).filter(|v| !v.is_empty());
+        Layer {
+            bind:   var("PIXEL_BIND"),
+            port:   var("PORT"),
+            db:     var("PIXEL_DB").map(PathBuf::from),
+            config: var("PIXEL_CONFIG").map(PathBuf::from),
+            require_params: var("PIXEL_REQUIRE_PARAMS"),
+            unregistered_sites: var("PIXEL_UNREGISTERED_SITES"),
+            bots: var("PIXEL_BOTS"),
+            bot_patterns: var("PIXEL_BOT_PATTERNS").map(PathBuf::from),
+            flush_interval: var("PIXEL_FLUSH_INTERVAL"),
+            flush_hits: var("PIXEL_FLUSH_HITS"),
+            shutdown_timeout: var("PIXEL_SHUTDOWN_TIMEOUT"),
+            log_format: var("PIXEL_LOG_FORMAT"),
+            command: Vec::new(),
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<Layer, ConfigError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|source| ConfigError::ReadFile { path: path.to_path_buf(), source })?;
+        let file: FileConfig = toml::from_str(&text)
+            .map_err(|source| ConfigError::ParseFile { path: path.to_path_buf(), source })?;
+        Ok(Layer {
+            bind:   file.bind,
+            port:   file.port.map(|p| p.to_string()),
+            db:     file.db,
+            config: None,
+            require_params: file.require_params.map(|b| b.to_string()),
+            unregistered_sites: file.unregistered_sites,
+            bots: file.bots,
+            bot_patterns: file.bot_patterns,
+            flush_interval: file.flush_interval.map(|ms| ms.to_string()),
+            flush_hits: file.flush_hits.map(|n| n.to_string()),
+            shutdown_timeout: file.shutdown_timeout.map(|secs| secs.to_string()),
+            log_format: file.log_format,
+            command: Vec::new(),
+        })
+    }
+
+    /// Fills in anything unset in `self` from `other`.
+    fn or(self, other: Layer) -> Layer {
+        Layer {
+            bind:   self.bind.or(other.bind),
+            port:   self.port.or(other.port),
+            db:     self.db.or(other.db),
+            config: self.config.or(other.config),
+            require_params: self.require_params.or(other.require_params),
+            unregistered_sites: self.unregistered_sites.or(other.unregistered_sites),
+            bots: self.bots.or(other.bots),
+            bot_patterns: self.bot_patterns.or(other.bot_patterns),
+            flush_interval: self.flush_interval.or(other.flush_interval),
+            flush_hits: self.flush_hits.or(other.flush_hits),
+            shutdown_timeout: self.shutdown_timeout.or(other.shutdown_timeout),
+            log_format: self.log_format.or(other.log_format),
+            command: self.command,
+        }
+    }
+}
+
+/// Loads the configuration for this process from `std::env::args` and the environment.
+pub fn load() -> Result<Config, ConfigError> {
+    let args = Layer::from_args(std::env::args().skip(1))?;
+    resolve(args, Layer::from_env(|name| std::env::var(name).ok()))
+}
+
+/// Combines the settings of the command line and the environment with those of
+/// the config file either of them names, and validates the result.
+fn resolve(args: Layer, env: Layer) -> Result<Config, ConfigError> {
+    let layer = args.or(env);
+    let layer = match layer.config.clone() {
+        Some(path) => layer.or(Layer::from_file(&path)?),
+        None => layer,
+    };
+
+    let bind = match layer.bind {
+        Some(bind) => bind.parse().map_err(|e: std::net::AddrParseError| ConfigError::Invalid {
+            key: "bind address".into(), value: bind, reason: e.to_string(),
+        })?,
+        None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+
+    let port = match layer.port {
+        Some(port) => match port.parse::<u16>() {
+            Ok(0) => return Err(ConfigError::Invalid { key: "port".into(), value: port, reason: "must be between 1 and 65535".into() }),
+            Ok(p) => p,
+            Err(e) => return Err(ConfigError::Invalid { key: "port".into(), value: port, reason: e.to_string() }),
+        },
+        None => 8080,
+    };
+
+    // Use /data for fly.io volume, fallback to ./data for local development
+    let db_path = layer.db.unwrap_or_else(|| {
+        if Path::new("/data").exists() {
+            PathBuf::from("/data/analytics.db")
+        } else {
+            PathBuf::from("data/analytics.db")
+        }
+    });
+    if db_path.as_os_str().is_empty() || db_path.is_dir() {
+        return Err(ConfigError::Invalid {
+            key: "database path".into(),
+            value: db_path.display().to_string(),
+            reason: "must be a file path".into(),
+        });
+    }
+
+    let require_params = layer.require_params
+        .map(|value| parse_bool("require_params", value))
+        .transpose()?
+        .unwrap_or(false);
+
+    let unregistered = match layer.unregistered_sites.as_deref() {
+        None | Some("count") => Unregistered::Count,
+        Some("drop") => Unregistered::Drop,
+        Some("log") => Unregistered::Log,
+        Some(other) => return Err(ConfigError::Invalid {
+            key: "unregistered_sites".into(),
+            value: other.into(),
+            reason: "expected count, drop or log".into(),
+        }),
+    };
+
+    let bots = match layer.bots.as_deref() {
+        Some("count") => Bots::Count,
+        Some("drop") => Bots::Drop,
+        None | Some("separate") => Bots::Separate,
+        Some(other) => return Err(ConfigError::Invalid {
+            key: "bots".into(),
+            value: other.into(),
+            reason: "expected count, drop or separate".into(),
+        }),
+    };
+
+    let flush_interval = Duration::from_millis(
+        layer.flush_interval.map(|value| parse_positive("flush_interval", value)).transpose()?.unwrap_or(1000) as u64,
+    );
+    let flush_hits = layer.flush_hits
+        .map(|value| parse_positive("flush_hits", value))
+        .transpose()?
+        .unwrap_or(1000);
+    let shutdown_timeout = Duration::from_secs(
+        layer.shutdown_timeout.map(|value| parse_positive("shutdown_timeout", value)).transpose()?.unwrap_or(5) as u64,
+    );
+
+    let log_format = match layer.log_format.as_deref() {
+        None | Some("text") => LogFormat::Text,
+        Some("json") => LogFormat::Json,
+        Some(other) => return Err(ConfigError::Invalid {
+            key: "log_format".into(),
+            value: other.into(),
+            reason: "expected text or json".into(),
+        }),
+    };
+
+    Ok(Config {
+        bind,
+        port,
+        db_path,
+        require_params,
+        unregistered,
+        bots,
+        bot_patterns: layer.bot_patterns,
+        flush_interval,
+        flush_hits,
+        shutdown_timeout,
+        log_format,
+        command: layer.command,
+    })
+}
+
+fn parse_bool(key: &str, value: String) -> Result<bool, ConfigError> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(ConfigError::Invalid { key: key.into(), value, reason: "expected true or false".into() }),
+    }
+}
+
+fn parse_positive(key: &str, value: String) -> Result<usize, ConfigError> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        Ok(_) => Err(ConfigError::Invalid { key: key.into(), value, reason: "must be at least 1".into() }),
+        Err(e) => Err(ConfigError::Invalid { key: key.into(), value, reason: e.to_string() }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn args(args: &[&str]) -> Layer {
+        Layer::from_args(args.iter().map(|a| a.to_string())).unwrap()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Layer {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        Layer::from_env(|name| vars.get(name).cloned())
+    }
+
+    /// Writes `contents` to a config file unique to the calling test.
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pixelpagecount-{}-{name}.toml", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn defaults_apply_when_nothing_is_set() {
+        let config = resolve(args(&["--db", "test.db"]), env(&[])).unwrap();
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.bind, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(config.unregistered, Unregistered::Count);
+        assert_eq!(config.bots, Bots::Separate);
+        assert_eq!(config.flush_interval, Duration::from_millis(1000));
+        assert!(config.command.is_empty());
+    }
+
+    #[test]
+    fn flags_take_precedence_over_environment_and_file() {
+        let path = config_file("flags", "port = 7000\nbots = \"drop\"\n");
+        let config = resolve(
+            args(&["--port=9000", "--db", "test.db", "--config", path.to_str().unwrap()]),
+            env(&[("PORT", "8000"), ("PIXEL_BOTS", "count")]),
+        ).unwrap();
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.bots, Bots::Count);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn environment_takes_precedence_over_file() {
+        let path = config_file("env", "port = 7000\nbind = \"127.0.0.1\"\n");
+        let config = resolve(
+            args(&["--db", "test.db"]),
+            env(&[("PORT", "8000"), ("PIXEL_CONFIG", path.to_str().unwrap())]),
+        ).unwrap();
+        assert_eq!(config.port, 8000);
+        // Settings missing from the environment still come from the file it names
+        assert_eq!(config.bind, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn empty_environment_variables_are_ignored() {
+        let config = resolve(args(&["--db", "test.db"]), env(&[("PORT", "")])).unwrap();
+        assert_eq!(config.port, 8080);
+    }
+
+    #[test]
+    fn or_keeps_set_values_and_the_command() {
+        let layer = args(&["--port", "9000", "sites", "list"]).or(env(&[("PORT", "8000"), ("PIXEL_BIND", "::1")]));
+        assert_eq!(layer.port.as_deref(), Some("9000"));
+        assert_eq!(layer.bind.as_deref(), Some("::1"));
+        assert_eq!(layer.command, ["sites", "list"]);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert!(matches!(resolve(args(&["--port", "0"]), env(&[])), Err(ConfigError::Invalid { .. })));
+        assert!(matches!(resolve(args(&["--bots", "maybe"]), env(&[])), Err(ConfigError::Invalid { .. })));
+        assert!(matches!(Layer::from_args(["--port".to_string()]), Err(ConfigError::MissingValue(_))));
+        assert!(matches!(Layer::from_args(["--nope".to_string()]), Err(ConfigError::UnknownArgument(_))));
+    }
+}
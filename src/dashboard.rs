@@ -0,0 +1,409 @@
+use axum::{
+    extract::{OriginalUri, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use rusqlite::{Connection, ToSql};
+use std::{collections::HashMap, fmt::Write};
+use time::{Date, Duration, OffsetDateTime};
+
+use crate::{
+    auth::{Access, Scope},
+    error::AppError,
+    extract::{Path, Query},
+    stats::{format_date, parse_date, Filter, GroupBy},
+    AppState,
+};
+
+/// Length of the period shown when `from` is not given.
+const DEFAULT_DAYS: i64 = 30;
+/// Longest period shown, to keep the chart readable.
+const MAX_DAYS: i64 = 366;
+/// Number of rows in the top pages and top referrers tables.
+const TOP_ROWS: i64 = 10;
+
+const CHART_WIDTH: f64 = 800.0;
+const CHART_HEIGHT: f64 = 200.0;
+
+const STYLE: &str = "
+body { font-family: system-ui, sans-serif; margin: 2em auto; max-width: 860px; color: #222; padding: 0 1em; }
+h1 { font-size: 1.5em; } h2 { font-size: 1.15em; margin-top: 2em; }
+a { color: #2563eb; text-decoration: none; } a:hover { text-decoration: underline; }
+table { border-collapse: collapse; width: 100%; }
+th, td { text-align: left; padding: .3em .5em; border-bottom: 1px solid #e5e7eb; }
+td.num, th.num { text-align: right; font-variant-numeric: tabular-nums; }
+.totals { display: flex; gap: 2em; }
+.totals div { font-size: .9em; color: #555; } .totals strong { display: block; font-size: 1.8em; color: #222; }
+form { margin: 1em 0; font-size: .9em; }
+svg { width: 100%; height: auto; } svg text { font-size: 11px; fill: #555; }
+.bar { fill: #93c5fd; } .bar:hover { fill: #2563eb; } .axis { stroke: #9ca3af; }
+.empty { color: #777; }
+";
+
+/// Query string accepted by the dashboard pages.
+#[derive(serde::Deserialize)]
+pub struct DashboardParams {
+    from: Option<String>,
+    to:   Option<String>,
+}
+
+struct Period {
+    from: Date,
+    to:   Date,
+}
+
+impl Period {
+    fn from_params(params: &DashboardParams) -> Result<Period, String> {
+        let to = params.to.as_deref().map(|d| parse_date("to", d)).transpose()?
+            .unwrap_or_else(|| OffsetDateTime::now_utc().date());
+        let from = match params.from.as_deref() {
+            Some(from) => parse_date("from", from)?,
+            None => to.checked_sub(Duration::days(DEFAULT_DAYS - 1))
+                .ok_or_else(|| format!("to ({to}) is too early"))?,
+        };
+        if from > to {
+            return Err(format!("from ({from}) is after to ({to})"));
+        }
+        if (to - from).whole_days() >= MAX_DAYS {
+            return Err(format!("the period can be at most {MAX_DAYS} days long"));
+        }
+        Ok(Period { from, to })
+    }
+
+    fn filter(&self, domain: Option<&str>) -> Filter {
+        Filter {
+            domain: domain.map(str::to_string),
+            page: None,
+            page_prefix: None,
+            from: Some(self.from),
+            to: Some(self.to),
+            group_by: GroupBy::Day,
+            limit: None,
+            offset: 0,
+        }
+    }
+
+    fn query_string(&self) -> String {
+        format!("from={}&to={}", format_date(self.from), format_date(self.to))
+    }
+}
+
+/// Overview of all domains with page views in the period.
+pub async fn overview(
+    State(state): State<AppState>,
+    access: Access,
+    Query(params): Query<DashboardParams>,
+) -> Response {
+    if access.scope == Scope::Public && access.public_domains.is_empty() {
+        return access.deny("API token required").into_response();
+    }
+    let period = match Period::from_params(&params) {
+        Ok(period) => period,
+        Err(e) => return error_page(StatusCode::BAD_REQUEST, &e),
+    };
+
+    // Tokens for a single domain only see that domain
+    let filter = period.filter(access.domain.as_deref());
+    let rows = state.reads.run(move |db| {
+        let (where_clause, params_vec) = filter.where_clause();
+        query_rows(
+            db,
+            &format!(
+                "SELECT domain, SUM(view_count) AS view_count, COUNT(DISTINCT page) FROM pageviews {where_clause}
+                 GROUP BY domain ORDER BY view_count DESC, domain"
+            ),
+            &params_vec,
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?)),
+        )
+    }).await;
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => return database_error(e),
+    };
+    // Without a token, only public sites are listed
+    let rows: Vec<_> = rows.into_iter()
+        .filter(|(domain, ..)| access.scope != Scope::Public || access.is_public(domain))
+        .collect();
+
+    let mut body = String::new();
+    body.push_str("<h1>Page views</h1>");
+    period_form(&mut body, &period, "/dashboard");
+    if rows.is_empty() {
+        body.push_str("<p class=\"empty\">No page views in this period.</p>");
+    } else {
+        body.push_str("<table><tr><th>Domain</th><th class=\"num\">Pages</th><th class=\"num\">Views</th></tr>");
+        for (domain, views, pages) in rows {
+            let _ = write!(
+                body,
+                "<tr><td><a href=\"/dashboard/{}?{}\">{}</a></td><td class=\"num\">{pages}</td><td class=\"num\">{views}</td></tr>",
+                escape(&encode_path_segment(&domain)),
+                escape(&period.query_string()),
+                escape(&domain),
+            );
+        }
+        body.push_str("</table>");
+    }
+
+    page("Page views", &body)
+}
+
+/// Totals, daily chart, top pages and top referrers for one domain.
+pub async fn site(
+    State(state): State<AppState>,
+    access: Access,
+    Path(domain): Path<String>,
+    Query(params): Query<DashboardParams>,
+) -> Response {
+    if let Err(denied) = access.restrict(&mut Some(domain.clone())) {
+        return denied.into_response();
+    }
+    let period = match Period::from_params(&params) {
+        Ok(period) => period,
+        Err(e) => return error_page(StatusCode::BAD_REQUEST, &e),
+    };
+    let action = format!("/dashboard/{}", encode_path_segment(&domain));
+    site_page(&state, &domain, &period, &action, true).await
+}
+
+/// The dashboard of a share link's domain, at `/share/{token}`.
+pub async fn shared(
+    State(state): State<AppState>,
+    access: Access,
+    OriginalUri(uri): OriginalUri,
+    Query(params): Query<DashboardParams>,
+) -> Response {
+    let Some(domain) = access.domain else {
+        return error_page(StatusCode::NOT_FOUND, "Unknown share link.");
+    };
+    let period = match Period::from_params(&params) {
+        Ok(period) => period,
+        Err(e) => return error_page(StatusCode::BAD_REQUEST, &e),
+    };
+    site_page(&state, &domain, &period, uri.path(), false).await
+}
+
+/// Renders the dashboard of `domain`, whose period form submits to `action`.
+async fn site_page(state: &AppState, domain: &str, period: &Period, action: &str, back_link: bool) -> Response {
+    let filter = period.filter(Some(domain));
+    let queried = state.reads.run(move |db| {
+        let (where_clause, params_vec) = filter.where_clause();
+        let daily: HashMap<String, i64> = query_rows(
+            db,
+            &format!("SELECT date, SUM(view_count) FROM pageviews {where_clause} GROUP BY date"),
+            &params_vec,
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+        )?.into_iter().collect();
+        let visitors = query_rows(
+            db,
+            &format!("SELECT COALESCE(SUM(visitor_count), 0) FROM site_visitors {where_clause}"),
+            &params_vec,
+            |row| row.get::<_, i64>(0),
+        )?.first().copied().unwrap_or(0);
+        let pages = query_rows(
+            db,
+            &format!("SELECT COUNT(DISTINCT page) FROM pageviews {where_clause}"),
+            &params_vec,
+            |row| row.get::<_, i64>(0),
+        )?.first().copied().unwrap_or(0);
+        let top_pages = query_rows(
+            db,
+            &format!(
+                "SELECT page, SUM(view_count) AS view_count FROM pageviews {where_clause}
+                 GROUP BY page ORDER BY view_count DESC, page LIMIT {TOP_ROWS}"
+            ),
+            &params_vec,
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+        )?;
+        let top_referrers = query_rows(
+            db,
+            &format!(
+                "SELECT referrer, SUM(view_count) AS view_count FROM referrers {where_clause}
+                 GROUP BY referrer ORDER BY view_count DESC, referrer LIMIT {TOP_ROWS}"
+            ),
+            &params_vec,
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+        )?;
+        Ok::<_, rusqlite::Error>((daily, visitors, pages, top_pages, top_referrers))
+    }).await;
+    let (daily, visitors, pages, top_pages, top_referrers) = match queried {
+        Ok(queried) => queried,
+        Err(e) => return database_error(e),
+    };
+
+    let total_views: i64 = daily.values().sum();
+    let title = format!("Page views for {domain}");
+
+    let mut body = String::new();
+    if back_link {
+        let _ = write!(body, "<p><a href=\"/dashboard?{}\">&larr; All domains</a></p>", escape(&period.query_string()));
+    }
+    let _ = write!(body, "<h1>{}</h1>", escape(&title));
+    period_form(&mut body, period, action);
+
+    let _ = write!(
+        body,
+        "<div class=\"totals\"><div><strong>{total_views}</strong>views</div>\
+         <div><strong>{visitors}</strong>visitors</div>\
+         <div><strong>{pages}</strong>pages</div></div>",
+    );
+
+    body.push_str("<h2>Views per day</h2>");
+    chart(&mut body, period, &daily);
+
+    body.push_str("<h2>Top pages</h2>");
+    table(&mut body, "Page", &top_pages);
+
+    body.push_str("<h2>Top referrers</h2>");
+    table(&mut body, "Referrer", &top_referrers);
+
+    page(&title, &body)
+}
+
+/// Runs `query`, returning the rows mapped by `f`.
+fn query_rows<T>(
+    db: &Connection,
+    query: &str,
+    params: &[Box<dyn ToSql + Send>],
+    f: impl FnMut(&rusqlite::Row<'_>) -> rusqlite::Result<T>,
+) -> rusqlite::Result<Vec<T>> {
+    let params_refs: Vec<&dyn ToSql> = params.iter().map(|p| p.as_ref() as &dyn ToSql).collect();
+    let mut stmt = db.prepare(query)?;
+    let rows = stmt.query_map(params_refs.as_slice(), f)?;
+    rows.collect()
+}
+
+/// Renders views per day as an SVG bar chart, with days without views as gaps.
+fn chart(out: &mut String, period: &Period, daily: &HashMap<String, i64>) {
+    let days = (period.to - period.from).whole_days() + 1;
+    let max = daily.values().copied().max().unwrap_or(0).max(1);
+    let (left, bottom) = (40.0, 20.0);
+    let plot_width = CHART_WIDTH - left;
+    let plot_height = CHART_HEIGHT - bottom;
+    let slot = plot_width / days as f64;
+
+    let _ = write!(
+        out,
+        "<svg viewBox=\"0 0 {CHART_WIDTH} {CHART_HEIGHT}\" role=\"img\" aria-label=\"Views per day\">\
+         <line class=\"axis\" x1=\"{left}\" y1=\"{plot_height}\" x2=\"{CHART_WIDTH}\" y2=\"{plot_height}\"/>\
+         <text x=\"{}\" y=\"10\" text-anchor=\"end\">{max}</text>\
+         <text x=\"{}\" y=\"{plot_height}\" text-anchor=\"end\">0</text>",
+        left - 4.0,
+        left - 4.0,
+    );
+
+    let mut date = period.from;
+    for i in 0..days {
+        let date_str = format_date(date);
+        let views = daily.get(&date_str).copied().unwrap_or(0);
+        if views > 0 {
+            let height = views as f64 / max as f64 * (plot_height - 12.0);
+            let _ = write!(
+                out,
+                "<rect class=\"bar\" x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\"><title>{date_str}: {views}</title></rect>",
+                left + i as f64 * slot + slot * 0.1,
+                plot_height - height,
+                slot * 0.8,
+                height,
+            );
+        }
+        date = date.next_day().unwrap_or(date);
+    }
+
+    let _ = write!(
+        out,
+        "<text x=\"{left}\" y=\"{CHART_HEIGHT}\">{}</text>\
+         <text x=\"{CHART_WIDTH}\" y=\"{CHART_HEIGHT}\" text-anchor=\"end\">{}</text></svg>",
+        format_date(period.from),
+        format_date(period.to),
+    );
+}
+
+fn table(out: &mut String, heading: &str, rows: &[(String, i64)]) {
+    if rows.is_empty() {
+        out.push_str("<p class=\"empty\">Nothing in this period.</p>");
+        return;
+    }
+    let _ = write!(out, "<table><tr><th>{heading}</th><th class=\"num\">Views</th></tr>");
+    for (name, views) in rows {
+        let _ = write!(out, "<tr><td>{}</td><td class=\"num\">{views}</td></tr>", escape(name));
+    }
+    out.push_str("</table>");
+}
+
+fn period_form(out: &mut String, period: &Period, action: &str) {
+    let _ = write!(
+        out,
+        "<form method=\"get\" action=\"{}\">\
+         <label>From <input type=\"date\" name=\"from\" value=\"{}\"></label> \
+         <label>To <input type=\"date\" name=\"to\" value=\"{}\"></label> \
+         <button type=\"submit\">Show</button></form>",
+        escape(action),
+        format_date(period.from),
+        format_date(period.to),
+    );
+}
+
+fn page(title: &str, body: &str) -> Response {
+    Html(format!(
+        "<!DOCTYPE html><html lang=\"en\"><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>{}</title><style>{STYLE}</style></head><body>{body}</body></html>",
+        escape(title),
+    )).into_response()
+}
+
+fn database_error(e: AppError) -> Response {
+    tracing::error!(error = %e, "cannot read database for dashboard");
+    error_page(StatusCode::INTERNAL_SERVER_ERROR, "Cannot read the database.")
+}
+
+fn error_page(status: StatusCode, message: &str) -> Response {
+    let mut response = page("Error", &format!("<h1>Error</h1><p>{}</p>", escape(message)));
+    *response.status_mut() = status;
+    response
+}
+
+/// Escapes `s` for use in HTML or SVG text and attribute values.
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Percent-encodes everything but unreserved characters, for use as one path segment.
+fn encode_path_segment(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "%{b:02X}");
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn period(from: Option<&str>, to: Option<&str>) -> Result<Period, String> {
+        Period::from_params(&DashboardParams { from: from.map(str::to_string), to: to.map(str::to_string) })
+    }
+
+    #[test]
+    fn period_defaults_to_last_days() {
+        let period = period(None, Some("2024-05-30")).unwrap();
+        assert_eq!((format_date(period.from), format_date(period.to)), ("2024-05-01".into(), "2024-05-30".into()));
+    }
+
+    #[test]
+    fn period_rejects_invalid_ranges() {
+        assert!(period(Some("2024-05-02"), Some("2024-05-01")).is_err());
+        assert!(period(Some("2023-01-01"), Some("2024-05-01")).is_err());
+        assert!(period(None, Some("-9999-01-01")).is_err());
+        assert!(period(Some("-9999-01-01"), Some("-9999-01-01")).is_ok());
+    }
+}
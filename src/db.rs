@@ -0,0 +1,152 @@
+use rusqlite::{Connection, OpenFlags};
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::{AppError, AppResult};
+
+/// How long a connection waits for a lock held by another connection.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Opens the database at `path`, creating it and its directory if needed. The
+/// schema is set up by `migrations::migrate`.
+pub fn open(path: &Path) -> Result<Connection, String> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("cannot create database directory {}: {e}", dir.display()))?;
+    }
+
+    let conn = Connection::open(path)
+        .map_err(|e| format!("cannot open database {}: {e}", path.display()))?;
+    conn.busy_timeout(BUSY_TIMEOUT)
+        .map_err(|e| format!("cannot configure database: {e}"))?;
+    // In WAL mode readers on other connections do not block the writer, nor the other way around
+    conn.pragma_update(None, "journal_mode", "WAL")
+        .map_err(|e| format!("cannot enable WAL mode: {e}"))?;
+    Ok(conn)
+}
+
+/// Opens the existing database at `path` as it is, for the `migrate` command,
+/// without creating it or changing its journal mode.
+pub fn open_existing(path: &Path) -> Result<Connection, String> {
+    if !path.exists() {
+        return Err(format!("database {} does not exist", path.display()));
+    }
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+        .map_err(|e| format!("cannot open database {}: {e}", path.display()))?;
+    conn.busy_timeout(BUSY_TIMEOUT)
+        .map_err(|e| format!("cannot configure database: {e}"))?;
+    Ok(conn)
+}
+
+/// Opens an additional, read-only connection to the database at `path`, which
+/// must already have been created with `open` and migrated.
+pub fn open_read_only(path: &Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(conn)
+}
+
+/// Runs `f` with the connection that writes on the blocking thread pool, as it
+/// may wait for `Writer` to write a batch. A panic in `f` is returned as
+/// `AppError::Internal`.
+pub async fn write<T: Send + 'static, E: Into<AppError> + Send + 'static>(
+    db: &Arc<Mutex<Connection>>,
+    f: impl FnOnce(&Connection) -> Result<T, E> + Send + 'static,
+) -> AppResult<T> {
+    let db = db.clone();
+    let written = tokio::task::spawn_blocking(move || f(&db.lock().unwrap_or_else(|e| e.into_inner()))).await;
+    match written {
+        Ok(result) => result.map_err(Into::into),
+        Err(e) => Err(AppError::Internal(format!("database write failed: {e}"))),
+    }
+}
+
+/// Read-only connections to the database, used for every query that does not write.
+///
+/// Queries run on Tokio's blocking thread pool, so a slow stats query neither holds
+/// up other requests on the same worker nor waits for the writer connection. At most
+/// `size` connections are open at a time; further queries wait for a free one.
+pub struct ReadPool {
+    path:    PathBuf,
+    idle:    Mutex<Vec<Connection>>,
+    permits: Arc<Semaphore>,
+}
+
+/// A connection taken from a `ReadPool`, returned to it when dropped.
+pub struct PooledConnection {
+    conn:    Option<Connection>,
+    pool:    Arc<ReadPool>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl ReadPool {
+    pub fn new(path: &Path, size: usize) -> ReadPool {
+        ReadPool {
+            path: path.to_path_buf(),
+            idle: Mutex::new(Vec::new()),
+            permits: Arc::new(Semaphore::new(size)),
+        }
+    }
+
+    /// Waits for a free connection, opening a new one if none is idle.
+    pub async fn get(self: &Arc<Self>) -> rusqlite::Result<PooledConnection> {
+        let permit = self.permits.clone().acquire_owned().await
+            .expect("read pool semaphore is never closed");
+        let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => open_read_only(&self.path)?,
+        };
+        Ok(PooledConnection { conn: Some(conn), pool: self.clone(), _permit: permit })
+    }
+
+    /// Runs `f` with a connection from the pool on the blocking thread pool. A panic
+    /// in `f` is returned as `AppError::Internal`.
+    pub async fn run<T: Send + 'static, E: Into<AppError> + Send + 'static>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&Connection) -> Result<T, E> + Send + 'static,
+    ) -> AppResult<T> {
+        let conn = self.get().await?;
+        match tokio::task::spawn_blocking(move || f(&conn)).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(e) => Err(AppError::Internal(format!("database query failed: {e}"))),
+        }
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("connection is only taken on drop")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.idle.lock().unwrap_or_else(|e| e.into_inner()).push(conn);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_existing_does_not_create_the_database() {
+        let dir = std::env::temp_dir().join(format!("ppc-open-existing-{}", std::process::id()));
+        let path = dir.join("missing.db");
+        assert!(open_existing(&path).unwrap_err().contains("does not exist"));
+        assert!(!dir.exists());
+    }
+}
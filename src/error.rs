@@ -0,0 +1,121 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use std::fmt;
+
+use crate::auth::Denied;
+
+/// Why a request to one of the JSON endpoints failed. Answered with a
+/// `{"error": "..."}` body and a matching status code.
+pub enum AppError {
+    BadRequest(String),
+    NotFound(String),
+    UnsupportedMediaType(String),
+    /// The request's token does not grant access to what was requested.
+    Denied(Denied),
+    Database(rusqlite::Error),
+    /// Any other failure of the server, such as a task that panicked.
+    Internal(String),
+}
+
+pub type AppResult<T = Response> = Result<T, AppError>;
+
+impl AppError {
+    pub fn bad_request(message: impl Into<String>) -> AppError {
+        AppError::BadRequest(message.into())
+    }
+
+    pub fn not_found(message: impl Into<String>) -> AppError {
+        AppError::NotFound(message.into())
+    }
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(e: rusqlite::Error) -> AppError {
+        AppError::Database(e)
+    }
+}
+
+impl From<Denied> for AppError {
+    fn from(denied: Denied) -> AppError {
+        AppError::Denied(denied)
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(e: serde_json::Error) -> AppError {
+        AppError::Internal(format!("cannot serialize response: {e}"))
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::BadRequest(message)
+            | AppError::NotFound(message)
+            | AppError::UnsupportedMediaType(message)
+            | AppError::Internal(message) => f.write_str(message),
+            AppError::Denied(denied) => denied.fmt(f),
+            AppError::Database(e) => write!(f, "database error: {e}"),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        match self {
+            AppError::BadRequest(message) => error_response(StatusCode::BAD_REQUEST, message),
+            AppError::NotFound(message) => error_response(StatusCode::NOT_FOUND, message),
+            AppError::UnsupportedMediaType(message) => error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, message),
+            AppError::Denied(denied) => denied.into_response(),
+            AppError::Database(e) => {
+                // The details stay in the log, as anyone may read public stats
+                tracing::error!(error = %e, "database error");
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+            }
+            AppError::Internal(message) => {
+                tracing::error!(error = %message, "internal error");
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, message)
+            }
+        }
+    }
+}
+
+pub fn error_response(status: StatusCode, message: impl Into<String>) -> Response {
+    (
+        status,
+        [("Content-Type", "application/json")],
+        serde_json::json!({ "error": message.into() }).to_string(),
+    ).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body(error: AppError) -> (StatusCode, String) {
+        let response = error.into_response();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn database_errors_are_not_shown_to_clients() {
+        let error = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(1),
+            Some("no such table: secret_stuff".into()),
+        );
+        let (status, body) = body(AppError::Database(error)).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body, r#"{"error":"database error"}"#);
+    }
+
+    #[tokio::test]
+    async fn bad_requests_explain_themselves() {
+        let (status, body) = body(AppError::bad_request("invalid limit")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, r#"{"error":"invalid limit"}"#);
+    }
+}
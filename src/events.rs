@@ -0,0 +1,185 @@
+use axum::{
+    body::Bytes,
+    extract::{rejection::QueryRejection, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::collections::BTreeMap;
+use time::OffsetDateTime;
+
+use crate::{
+    beacon,
+    config::Bots,
+    error::{AppError, AppResult},
+    hits,
+    sites::Verdict,
+    stats, AppState, PIXEL_GIF,
+};
+
+/// Longest event name accepted, in characters.
+const MAX_NAME: usize = 64;
+/// Most properties one event may carry.
+const MAX_PROPS: usize = 5;
+const MAX_PROP_KEY: usize = 32;
+const MAX_PROP_VALUE: usize = 128;
+/// Prefix of the query parameters holding properties on `GET /event`, as in `props.plan=pro`.
+const PROP_PREFIX: &str = "props.";
+
+/// Something that happened on a page, such as a click or a download, as
+/// received by `/event`.
+pub struct Event {
+    pub domain: Option<String>,
+    pub page:   Option<String>,
+    pub name:   String,
+    pub props:  BTreeMap<String, String>,
+}
+
+/// Body of `POST /event`.
+#[derive(serde::Deserialize)]
+struct EventBody {
+    domain: Option<String>,
+    page:   Option<String>,
+    name:   String,
+    #[serde(default)]
+    props:  BTreeMap<String, String>,
+}
+
+impl Event {
+    /// Takes the event from query parameters, ignoring unknown ones such as cache busters.
+    fn from_query(params: Vec<(String, String)>) -> Event {
+        let mut event = Event { domain: None, page: None, name: String::new(), props: BTreeMap::new() };
+        for (key, value) in params {
+            match key.as_str() {
+                "domain" => event.domain = Some(value),
+                "page"   => event.page = Some(value),
+                "name"   => event.name = value,
+                _ => {
+                    if let Some(prop) = key.strip_prefix(PROP_PREFIX) {
+                        event.props.insert(prop.to_string(), value);
+                    }
+                }
+            }
+        }
+        event
+    }
+
+    /// Checks that the name and properties are present and small.
+    fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() || self.name.chars().count() > MAX_NAME {
+            return Err(format!("name must be 1 to {MAX_NAME} characters"));
+        }
+        if self.props.len() > MAX_PROPS {
+            return Err(format!("an event can have at most {MAX_PROPS} properties"));
+        }
+        for (key, value) in &self.props {
+            if key.is_empty() || key.chars().count() > MAX_PROP_KEY {
+                return Err(format!("property names must be 1 to {MAX_PROP_KEY} characters"));
+            }
+            if value.chars().count() > MAX_PROP_VALUE {
+                return Err(format!("property '{key}' can be at most {MAX_PROP_VALUE} characters"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `GET /event?domain=<domain>&page=<page>&name=<name>&props.<key>=<value>`:
+/// counts the event and, like `/counter.gif`, always returns the GIF.
+pub async fn track_pixel(
+    State(state): State<AppState>,
+    headers:      HeaderMap,
+    params:       Result<Query<Vec<(String, String)>>, QueryRejection>,
+) -> impl IntoResponse {
+    let event = params.map_err(|e| e.body_text())
+        .map(|Query(params)| Event::from_query(params))
+        .and_then(|event| event.validate().map(|()| event));
+    match event {
+        Ok(event) => hits::contain("event", async move { record(&state, event, &headers).await }).await,
+        Err(e) => tracing::debug!(error = %e, "invalid event dropped"),
+    }
+
+    (
+        [("Content-Type", "image/gif")],
+        PIXEL_GIF
+    )
+}
+
+/// `POST /event`: counts the event described by a JSON body. Like `/api/hit`, the
+/// body may be sent as `application/json` or as `text/plain`, as `navigator.sendBeacon`
+/// does for strings, and registered sites may read the answer from other origins.
+pub async fn track_beacon(
+    State(state): State<AppState>,
+    headers:      HeaderMap,
+    body:         Bytes,
+) -> Response {
+    let origin = beacon::allowed_origin(&state, &headers);
+    let response = accept(&state, &headers, &body).await.into_response();
+    beacon::with_cors(response, origin)
+}
+
+async fn accept(state: &AppState, headers: &HeaderMap, body: &[u8]) -> AppResult {
+    beacon::check_media_type(headers)?;
+    let body: EventBody = serde_json::from_slice(body)
+        .map_err(|e| AppError::bad_request(format!("invalid event: {e}")))?;
+    let event = Event { domain: body.domain, page: body.page, name: body.name, props: body.props };
+    event.validate().map_err(AppError::BadRequest)?;
+    record(state, event, headers).await;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Counts `event` for the current day, unless it is dropped by the site registry
+/// or sent by a bot. Events of bots are not counted unless bots are counted as
+/// visitors, as they are unlikely to be real clicks.
+pub async fn record(state: &AppState, event: Event, headers: &HeaderMap) {
+    state.metrics.event();
+    let referer = headers.get(header::REFERER).and_then(|h| h.to_str().ok());
+    let user_agent = headers.get(header::USER_AGENT).and_then(|h| h.to_str().ok());
+
+    let Some((domain, page)) = hits::target(state, event.domain, event.page, referer) else {
+        return;
+    };
+    let verdict = hits::verdict(state, &domain, referer);
+    let bot = state.config.bots != Bots::Count && state.bots.classify(user_agent).is_some();
+    let date = stats::format_date(OffsetDateTime::now_utc().date());
+
+    match verdict {
+        Verdict::Drop => {}
+        Verdict::Log => state.writer.record(|pending| pending.unregistered_hit(&domain, &date)),
+        Verdict::Count if bot => {}
+        Verdict::Count => state.writer.record(|pending| {
+            pending.event(&domain, &page, &date, &event.name);
+            for (key, value) in &event.props {
+                pending.event_prop(&domain, &page, &date, &event.name, key, value);
+            }
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(pairs: &[(&str, &str)]) -> Event {
+        Event::from_query(pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect())
+    }
+
+    #[test]
+    fn takes_event_from_query() {
+        let event = query(&[("name", "signup"), ("domain", "example.com"), ("props.plan", "pro"), ("_", "123")]);
+        assert_eq!(event.name, "signup");
+        assert_eq!(event.domain.as_deref(), Some("example.com"));
+        assert_eq!(event.page, None);
+        assert_eq!(event.props, BTreeMap::from([("plan".to_string(), "pro".to_string())]));
+        assert!(event.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_events() {
+        assert!(query(&[]).validate().is_err());
+        assert!(query(&[("name", &"x".repeat(MAX_NAME + 1))]).validate().is_err());
+        assert!(query(&[("name", "a"), ("props.", "b")]).validate().is_err());
+        assert!(query(&[("name", "a"), ("props.k", &"v".repeat(MAX_PROP_VALUE + 1))]).validate().is_err());
+        let props: Vec<_> = (0..=MAX_PROPS).map(|i| (format!("props.{i}"), "v".to_string())).collect();
+        assert!(Event::from_query([vec![("name".into(), "a".into())], props].concat()).validate().is_err());
+    }
+}
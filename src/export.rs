@@ -0,0 +1,217 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use rusqlite::{Connection, ToSql};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    auth::Access,
+    error::{AppError, AppResult},
+    extract::Query,
+    stats::{paged, Filter, StatsParams},
+    AppState,
+};
+
+/// Rows are sent to the client in chunks of about this many bytes.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Formats in which all matching records can be downloaded in one response.
+#[derive(Clone, Copy)]
+pub enum Format {
+    Csv,
+    Ndjson,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Result<Format, String> {
+        match s {
+            "csv"    => Ok(Format::Csv),
+            "ndjson" => Ok(Format::Ndjson),
+            _ => Err(format!("invalid format '{s}', expected csv or ndjson")),
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Csv    => "text/csv; charset=utf-8",
+            Format::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn file_name(self) -> &'static str {
+        match self {
+            Format::Csv    => "stats.csv",
+            Format::Ndjson => "stats.ndjson",
+        }
+    }
+
+    fn header(self, out: &mut String) {
+        match self {
+            Format::Csv    => out.push_str("date,domain,page,view_count,visitors\r\n"),
+            Format::Ndjson => {}
+        }
+    }
+
+    fn row(self, out: &mut String, domain: &str, page: &str, date: &str, view_count: i64, visitors: i64) {
+        match self {
+            Format::Csv => {
+                for field in [date, domain, page] {
+                    push_csv_field(out, field);
+                    out.push(',');
+                }
+                out.push_str(&format!("{view_count},{visitors}\r\n"));
+            }
+            Format::Ndjson => {
+                let record = serde_json::json!({
+                    "domain": domain,
+                    "page": page,
+                    "date": date,
+                    "view_count": view_count,
+                    "visitors": visitors
+                });
+                out.push_str(&record.to_string());
+                out.push('\n');
+            }
+        }
+    }
+}
+
+/// Appends `field`, quoted as per RFC 4180 if it contains a separator, quote or line break.
+///
+/// Fields are sent by clients, so one that a spreadsheet would take as a formula is
+/// prefixed with `'`, which makes it text.
+fn push_csv_field(out: &mut String, field: &str) {
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{field}")
+    } else {
+        field.to_string()
+    };
+    if field.contains([',', '"', '\r', '\n']) {
+        out.push('"');
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(&field);
+    }
+}
+
+pub async fn export_csv(
+    State(state): State<AppState>,
+    access: Access,
+    Query(params): Query<StatsParams>,
+) -> AppResult {
+    let mut filter = Filter::from_params(params).map_err(AppError::BadRequest)?;
+    access.restrict(&mut filter.domain)?;
+    Ok(stream(state, filter, Format::Csv).await)
+}
+
+/// Streams every record matching `filter` in `format`. Unlike the JSON endpoint,
+/// this is not paginated; `limit` and `offset` only apply when given.
+///
+/// Rows are read on a connection of the export pool while the client consumes them,
+/// so a long download neither buffers the whole result nor holds up counting or
+/// other stats queries.
+pub async fn stream(state: AppState, filter: Filter, format: Format) -> Response {
+    let db = match state.exports.get().await {
+        Ok(db) => db,
+        Err(e) => return AppError::Database(e).into_response(),
+    };
+    let (tx, rx) = mpsc::channel(4);
+
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = send_rows(&db, &filter, format, &tx) {
+            tracing::error!(error = %e, "export failed");
+            // Aborts the response, so the client does not mistake it for a complete export
+            let _ = tx.blocking_send(Err(std::io::Error::other(e)));
+        }
+    });
+
+    (
+        [
+            ("Content-Type", format.content_type().to_string()),
+            ("Content-Disposition", format!("attachment; filename=\"{}\"", format.file_name())),
+        ],
+        Body::from_stream(ReceiverStream::new(rx)),
+    ).into_response()
+}
+
+type Chunks = mpsc::Sender<Result<Bytes, std::io::Error>>;
+
+fn send_rows(db: &Connection, filter: &Filter, format: Format, tx: &Chunks) -> rusqlite::Result<()> {
+    let (where_clause, params_vec) = filter.where_clause();
+    let params_refs: Vec<&dyn ToSql> = params_vec.iter().map(|p| p.as_ref() as &dyn ToSql).collect();
+    let query = format!("{} ORDER BY period DESC, domain, page LIMIT ? OFFSET ?", filter.records_sql(&where_clause));
+    // A negative limit means no limit to SQLite
+    let (limit, offset) = (filter.limit.map_or(-1, |l| l as i64), filter.offset as i64);
+
+    let mut stmt = db.prepare(&query)?;
+    let rows = stmt.query_map(paged(&params_refs, &limit, &offset).as_slice(), |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, i64>(3)?,
+            row.get::<_, i64>(4)?
+        ))
+    })?;
+
+    let mut buf = String::new();
+    format.header(&mut buf);
+    for row in rows {
+        let (domain, page, date, view_count, visitors) = row?;
+        format.row(&mut buf, &domain, &page, &date, view_count, visitors);
+
+        // Stop early if the client has gone away
+        if buf.len() >= CHUNK_SIZE && tx.blocking_send(Ok(Bytes::from(std::mem::take(&mut buf)))).is_err() {
+            return Ok(());
+        }
+    }
+    let _ = tx.blocking_send(Ok(Bytes::from(buf)));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csv_field(field: &str) -> String {
+        let mut out = String::new();
+        push_csv_field(&mut out, field);
+        out
+    }
+
+    #[test]
+    fn plain_fields_are_not_quoted() {
+        assert_eq!(csv_field("/blog/post"), "/blog/post");
+        assert_eq!(csv_field(""), "");
+    }
+
+    #[test]
+    fn fields_with_separators_quotes_or_line_breaks_are_quoted() {
+        assert_eq!(csv_field("/a,b"), "\"/a,b\"");
+        assert_eq!(csv_field("/say \"hi\""), "\"/say \"\"hi\"\"\"");
+        assert_eq!(csv_field("/line\nbreak"), "\"/line\nbreak\"");
+        assert_eq!(csv_field("/carriage\rreturn"), "\"/carriage\rreturn\"");
+    }
+
+    #[test]
+    fn fields_that_look_like_formulas_are_made_text() {
+        assert_eq!(csv_field("=HYPERLINK(\"http://evil.test\")"), "\"'=HYPERLINK(\"\"http://evil.test\"\")\"");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-2+3"), "'-2+3");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("\tx"), "'\tx");
+        assert_eq!(csv_field("/a=b"), "/a=b");
+    }
+
+    #[test]
+    fn csv_rows_end_with_crlf() {
+        let mut out = String::new();
+        Format::Csv.header(&mut out);
+        Format::Csv.row(&mut out, "example.com", "/a,b", "2025-01-01", 3, 2);
+        assert_eq!(out, "date,domain,page,view_count,visitors\r\n2025-01-01,example.com,\"/a,b\",3,2\r\n");
+    }
+}
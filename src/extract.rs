@@ -0,0 +1,74 @@
+use axum::{
+    extract::{
+        rejection::{PathRejection, QueryRejection},
+        FromRequestParts,
+    },
+    http::request::Parts,
+};
+use serde::de::DeserializeOwned;
+
+use crate::error::AppError;
+
+/// Like axum's `Query`, but an invalid query string is answered with a JSON error.
+pub struct Query<T>(pub T);
+
+/// Like axum's `Path`, but invalid path parameters are answered with a JSON error.
+pub struct Path<T>(pub T);
+
+impl<T: DeserializeOwned, S: Send + Sync> FromRequestParts<S> for Query<T> {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Query<T>, AppError> {
+        let axum::extract::Query(value) = axum::extract::Query::from_request_parts(parts, state).await?;
+        Ok(Query(value))
+    }
+}
+
+impl<T: DeserializeOwned + Send, S: Send + Sync> FromRequestParts<S> for Path<T> {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Path<T>, AppError> {
+        let axum::extract::Path(value) = axum::extract::Path::from_request_parts(parts, state).await?;
+        Ok(Path(value))
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> AppError {
+        AppError::BadRequest(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for AppError {
+    fn from(rejection: PathRejection) -> AppError {
+        match rejection {
+            PathRejection::FailedToDeserializePathParams(e) => AppError::BadRequest(e.body_text()),
+            // The route does not match the extractor, which is a bug rather than a bad request
+            other => AppError::Internal(other.body_text()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+
+    #[derive(serde::Deserialize)]
+    struct Params {
+        limit: Option<u32>,
+    }
+
+    async fn query(uri: &str) -> Result<Query<Params>, AppError> {
+        let (mut parts, ()) = Request::get(uri).body(()).unwrap().into_parts();
+        Query::from_request_parts(&mut parts, &()).await
+    }
+
+    #[tokio::test]
+    async fn invalid_query_is_bad_request() {
+        assert_eq!(query("/stats.json?limit=5").await.ok().and_then(|Query(params)| params.limit), Some(5));
+        for uri in ["/stats.json?limit=5&limit=6", "/stats.json?limit=many"] {
+            assert!(matches!(query(uri).await, Err(AppError::BadRequest(_))), "{uri}");
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use std::{io::Write, path::Path};
+
+use crate::{migrations, AppState};
+
+/// `GET /healthz`: the process is running and answering requests.
+pub async fn healthz() -> Response {
+    Json(serde_json::json!({ "status": "ok" })).into_response()
+}
+
+/// `GET /readyz`: the service can count and report page views. Answers
+/// `503 Service Unavailable` with the failing checks otherwise.
+pub async fn readyz(State(state): State<AppState>) -> Response {
+    let schema = state.reads.run(migrations::schema_version).await;
+    let (database, schema) = match schema {
+        Ok(version) if version == migrations::SCHEMA_VERSION => (Ok(()), Ok(())),
+        Ok(version) => (Ok(()), Err(format!("schema version {version}, expected {}", migrations::SCHEMA_VERSION))),
+        Err(e) => (Err(format!("cannot query database: {e}")), Err("not checked".into())),
+    };
+
+    let db_path = state.config.db_path.clone();
+    let disk = tokio::task::spawn_blocking(move || check_writable(&db_path)).await
+        .unwrap_or_else(|e| Err(e.to_string()));
+
+    let writes = match state.writer.last_error() {
+        None => Ok(()),
+        Some(e) => Err(format!("last write failed: {e}")),
+    };
+
+    let checks = [("database", database), ("schema", schema), ("disk", disk), ("writes", writes)];
+    let ready = checks.iter().all(|(_, result)| result.is_ok());
+    let checks: serde_json::Map<String, serde_json::Value> = checks.into_iter()
+        .map(|(name, result)| (name.to_string(), result.err().unwrap_or_else(|| "ok".into()).into()))
+        .collect();
+
+    if !ready {
+        tracing::warn!(checks = %serde_json::Value::Object(checks.clone()), "not ready");
+    }
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    let result = serde_json::json!({
+        "status": if ready { "ok" } else { "unavailable" },
+        "checks": checks
+    });
+    (status, Json(result)).into_response()
+}
+
+/// Writes, syncs and removes a small file next to the database, which fails on
+/// a full disk or a volume mounted read-only.
+fn check_writable(db_path: &Path) -> Result<(), String> {
+    let dir = db_path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let probe = dir.join(format!(".readyz-{}", std::process::id()));
+    let result = std::fs::File::create(&probe)
+        .and_then(|mut file| {
+            file.write_all(b"ok")?;
+            file.sync_all()
+        })
+        .map_err(|e| format!("cannot write to {}: {e}", dir.display()));
+    let _ = std::fs::remove_file(&probe);
+    result
+}
@@ -0,0 +1,108 @@
+use axum::http::{header, HeaderMap};
+use std::{future::Future, net::SocketAddr};
+use time::OffsetDateTime;
+
+use crate::{config::Bots, referrer, sites::Verdict, stats, visitors, AppState};
+
+/// A page view as received by one of the counting endpoints.
+pub struct Hit<'a> {
+    pub domain:   Option<String>,
+    pub page:     Option<String>,
+    /// The viewed page's `document.referrer`, if passed explicitly
+    pub referrer: Option<&'a str>,
+    pub headers:  &'a HeaderMap,
+    pub peer:     SocketAddr,
+}
+
+/// Records `hit` unless it is dropped by the site registry or bot filtering. Its
+/// counts are buffered and written to the database shortly after.
+///
+/// Returns the domain and page the hit was for, or `None` if they could not be
+/// determined.
+pub async fn record(state: &AppState, hit: Hit<'_>) -> Option<(String, String)> {
+    state.metrics.pixel_hit();
+    let headers = hit.headers;
+    let referer = headers.get(header::REFERER).and_then(|h| h.to_str().ok());
+    let user_agent = headers.get(header::USER_AGENT).and_then(|h| h.to_str().ok());
+
+    let (domain, page) = target(state, hit.domain, hit.page, referer)?;
+    let date = OffsetDateTime::now_utc().date();
+    let date_str = stats::format_date(date);
+    let referrer = referrer::referring_host(hit.referrer, referer, &domain);
+
+    let verdict = verdict(state, &domain, referer);
+    if matches!(verdict, Verdict::Drop) {
+        return Some((domain, page));
+    }
+    let bot = state.bots.classify(user_agent);
+    // Only hits counted as page views can be new visitors
+    let counted = matches!(verdict, Verdict::Count) && (bot.is_none() || state.config.bots == Bots::Count);
+    let new_visitor = counted.then(|| {
+        let ip = visitors::client_ip(headers, hit.peer);
+        state.visitors.record(date, &domain, &page, ip, user_agent.unwrap_or(""))
+    });
+
+    state.writer.record(|pending| {
+        if matches!(verdict, Verdict::Log) {
+            return pending.unregistered_hit(&domain, &date_str);
+        }
+        if let Some(bot) = bot {
+            if state.config.bots == Bots::Separate {
+                pending.bot_hit(&domain, &page, &date_str, bot);
+            }
+            if state.config.bots != Bots::Count {
+                return;
+            }
+        }
+
+        pending.page_view(&domain, &page, &date_str);
+        if let Some(referrer) = &referrer {
+            pending.referrer(&domain, &page, &date_str, referrer);
+        }
+        if let Some(new_visitor) = &new_visitor {
+            if new_visitor.to_page {
+                pending.visitor(&domain, &page, &date_str);
+            }
+            if new_visitor.to_site {
+                pending.site_visitor(&domain, &date_str);
+            }
+        }
+    });
+
+    Some((domain, page))
+}
+
+/// Runs `record` in a task of its own and waits for it, so that a panic while
+/// recording is logged instead of failing a request that must always succeed.
+pub async fn contain(what: &str, record: impl Future<Output = ()> + Send + 'static) {
+    if let Err(e) = tokio::spawn(record).await {
+        tracing::error!(error = %e, "cannot record {what}");
+    }
+}
+
+/// The domain and page a hit is for. Falls back to the page embedding the pixel,
+/// taken from `referer`, when they are not given, unless parameters are required.
+pub fn target(
+    state: &AppState,
+    domain: Option<String>,
+    page: Option<String>,
+    referer: Option<&str>,
+) -> Option<(String, String)> {
+    match (domain, page) {
+        (Some(domain), Some(page)) => Some((domain, page)),
+        _ if state.config.require_params => None,
+        (domain, page) => {
+            let inferred = referer.and_then(referrer::parse_page);
+            let (inferred_domain, inferred_page) = inferred.unzip();
+            Some((
+                domain.or(inferred_domain).unwrap_or_else(|| "unknown".into()),
+                page.or(inferred_page).unwrap_or_else(|| "/unknown".into()),
+            ))
+        }
+    }
+}
+
+/// Whether a hit for `domain` is counted, as decided by the site registry.
+pub fn verdict(state: &AppState, domain: &str, referer: Option<&str>) -> Verdict {
+    state.sites.check(state.config.unregistered, domain, referer)
+}
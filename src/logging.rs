@@ -0,0 +1,53 @@
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::{io::IsTerminal, time::Instant};
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::LogFormat;
+
+/// Environment variable holding the log filter, e.g. `info` or `warn,pixelpagecount=debug`.
+const FILTER_VAR: &str = "PIXEL_LOG";
+
+/// Sends log events to stderr in `format`, at the levels set by `PIXEL_LOG`
+/// (or `RUST_LOG`), `info` by default.
+pub fn init(format: LogFormat) {
+    let filter = EnvFilter::try_from_env(FILTER_VAR)
+        .or_else(|_| EnvFilter::try_from_default_env())
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(false).init(),
+    }
+}
+
+/// Middleware running each request in a span with its method and route, and
+/// logging its status and latency when the response starts.
+///
+/// Only the route pattern is logged, never the path, query string or headers, so
+/// logs hold no client addresses, user agents or share link tokens.
+pub async fn trace_requests(matched: Option<MatchedPath>, request: Request, next: Next) -> Response {
+    let route = matched.as_ref().map_or("(unmatched)", |path| path.as_str()).to_string();
+    let span = tracing::info_span!("request", method = %request.method(), route);
+    let started = Instant::now();
+
+    let response = next.run(request).instrument(span.clone()).await;
+
+    let status = response.status().as_u16();
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    span.in_scope(|| {
+        if response.status().is_server_error() {
+            tracing::error!(status, latency_ms, "request failed");
+        } else {
+            tracing::info!(status, latency_ms, "request");
+        }
+    });
+    response
+}
@@ -1,140 +1,276 @@
 use axum::{
-    extract::{Query, State},
-    routing::get,
+    extract::{rejection::QueryRejection, ConnectInfo, DefaultBodyLimit, Query, State},
+    http::HeaderMap,
+    middleware,
+    routing::{delete, get, post, put},
     Router,
     response::IntoResponse,
 };
 use rusqlite::Connection;
-use std::{sync::{Arc, Mutex}, net::SocketAddr, path::Path};
+use std::{future::IntoFuture, net::SocketAddr, process::ExitCode, sync::{Arc, Mutex}};
+use tokio::sync::oneshot;
 use time::OffsetDateTime;
 
+mod auth;
+mod badge;
+mod beacon;
+mod bots;
+mod config;
+mod dashboard;
+mod db;
+mod error;
+mod events;
+mod export;
+mod extract;
+mod health;
+mod hits;
+mod logging;
+mod metrics;
+mod migrations;
+mod referrer;
+mod share;
+mod sites;
+mod stats;
+mod visitors;
+mod writer;
+
 static PIXEL_GIF: &[u8] = b"GIF89a\
 \x01\x00\x01\x00\x80\x00\x00\
 \x00\x00\x00\xFF\xFF\xFF!\xF9\x04\x01\x00\x00\
 \x00\x00,\x00\x00\x00\x00\x01\x00\x01\x00\
 \x00\x02\x02D\x01\x00;";
 
+/// Number of read-only connections used for stats queries, in addition to the
+/// connection that writes.
+const READ_CONNECTIONS: usize = 8;
+/// Number of read-only connections used by exports, which hold one for the whole
+/// download, kept apart from `READ_CONNECTIONS` so they cannot take them all.
+const EXPORT_CONNECTIONS: usize = 2;
+
 #[derive(Clone)]
 struct AppState {
-    db: Arc<Mutex<Connection>>,
+    /// The only connection that writes, used by `writer` and site management.
+    db:     Arc<Mutex<Connection>>,
+    reads:  Arc<db::ReadPool>,
+    exports: Arc<db::ReadPool>,
+    sites:  Arc<sites::Registry>,
+    config: Arc<config::Config>,
+    bots:   Arc<bots::BotDetector>,
+    visitors: Arc<visitors::VisitorTracker>,
+    writer: Arc<writer::Writer>,
+    metrics: Arc<metrics::Metrics>,
 }
 
 #[tokio::main]
-async fn main() {
-    // Use /data for fly.io volume, fallback to ./data for local development
-    let db_path = if Path::new("/data").exists() {
-        "/data/analytics.db"
-    } else {
-        std::fs::create_dir_all("data").unwrap();
-        "data/analytics.db"
+async fn main() -> ExitCode {
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(config::ConfigError::Help) => {
+            println!("{}", config::ConfigError::Help);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::FAILURE;
+        }
     };
 
-    let conn = Connection::open(db_path).unwrap();
-    conn.execute_batch(
-        "CREATE TABLE IF NOT EXISTS pageviews (
-            domain TEXT NOT NULL,
-            page TEXT NOT NULL,
-            date TEXT NOT NULL,
-            view_count INTEGER NOT NULL DEFAULT 1,
-            PRIMARY KEY (domain, page, date)
-        );"
-    ).unwrap();
+    logging::init(config.log_format);
+
+    match run(config).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(config: config::Config) -> Result<(), String> {
+    if config.command.first().map(String::as_str) == Some("migrate") {
+        let mut conn = db::open_existing(&config.db_path)?;
+        return migrations::run_command(&mut conn, &config.command[1..]);
+    }
+
+    let mut conn = db::open(&config.db_path)?;
+    if let Some(migration) = migrations::migrate(&mut conn)?.last() {
+        tracing::info!(version = migration.version, "migrated database schema");
+    }
+
+    match config.command.first().map(String::as_str) {
+        None | Some("serve") => {}
+        Some("sites") => return sites::run_command(&conn, &config.command[1..]),
+        Some("tokens") => return auth::run_command(&conn, &config.command[1..]),
+        Some(other) => return Err(format!("unknown command '{other}', see --help")),
+    }
 
-    let state = AppState { db: Arc::new(Mutex::new(conn)) };
+    let bots = bots::BotDetector::new(config.bot_patterns.as_deref())?;
+    let registry = sites::Registry::load(&conn).map_err(|e| format!("cannot load registered sites: {e}"))?;
+
+    let addr = config.addr();
+    let db = Arc::new(Mutex::new(conn));
+    let metrics = Arc::new(metrics::Metrics::default());
+    let writer = Arc::new(writer::Writer::new(db.clone(), metrics.clone(), config.flush_hits));
+    let flusher = tokio::spawn(writer.clone().run(config.flush_interval));
+    let state = AppState {
+        db:     db.clone(),
+        reads:  Arc::new(db::ReadPool::new(&config.db_path, READ_CONNECTIONS)),
+        exports: Arc::new(db::ReadPool::new(&config.db_path, EXPORT_CONNECTIONS)),
+        sites:  Arc::new(registry),
+        config: Arc::new(config.clone()),
+        bots:   Arc::new(bots),
+        visitors: Arc::new(visitors::VisitorTracker::new(OffsetDateTime::now_utc().date())?),
+        writer: writer.clone(),
+        metrics,
+    };
+    tokio::spawn(sites::refresh(state.sites.clone(), state.reads.clone()));
+
+    // Read-only stats of one domain, for anyone with a share link
+    let shared = Router::new()
+        .route("/",           get(dashboard::shared))
+        .route("/stats.json", get(stats::export))
+        .route("/stats.csv",  get(export::export_csv))
+        .route("/stats/top.json", get(stats::top_pages))
+        .route("/stats/referrers.json", get(stats::top_referrers))
+        .route("/stats/events.json", get(stats::events))
+        .route("/badge.svg",  get(badge::badge))
+        .route_layer(middleware::from_fn_with_state(state.clone(), share::authorize))
+        .route_layer(middleware::from_fn_with_state(state.clone(), metrics::time_stats));
+
+    let stats = Router::new()
+        .route("/stats.json",  get(stats::export))
+        .route("/stats.csv",   get(export::export_csv))
+        .route("/stats/top.json", get(stats::top_pages))
+        .route("/stats/referrers.json", get(stats::top_referrers))
+        .route("/stats/bots.json", get(stats::bots))
+        .route("/stats/events.json", get(stats::events))
+        .route("/dashboard", get(dashboard::overview))
+        .route("/dashboard/{domain}", get(dashboard::site))
+        .route_layer(middleware::from_fn_with_state(state.clone(), metrics::time_stats));
 
     let app = Router::new()
+        .route("/healthz",     get(health::healthz))
+        .route("/readyz",      get(health::readyz))
+        .route("/metrics",     get(metrics::metrics))
         .route("/counter.gif", get(count_page_view))
-        .route("/stats.json",  get(export))
+        .route("/badge.svg",   get(badge::badge))
+        .route("/event",       get(events::track_pixel).post(events::track_beacon).options(beacon::preflight).layer(DefaultBodyLimit::max(beacon::MAX_BODY)))
+        .route("/api/hit",     post(beacon::hit).options(beacon::preflight).layer(DefaultBodyLimit::max(beacon::MAX_BODY)))
+        .merge(stats)
+        .route("/admin/sites", get(sites::list_sites))
+        .route("/admin/sites/{domain}", put(sites::put_site).delete(sites::delete_site))
+        .route("/admin/sites/{domain}/shares", get(share::list_links).post(share::create_link))
+        .route("/admin/sites/{domain}/shares/{id}", delete(share::delete_link))
+        .nest("/share/{token}", shared)
+        .layer(middleware::from_fn(logging::trace_requests))
         .with_state(state);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
-    println!("Listening on {addr}");
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
-}
+    let listener = tokio::net::TcpListener::bind(&addr).await
+        .map_err(|e| format!("cannot listen on {addr}: {e}"))?;
+    tracing::info!(%addr, database = %config.db_path.display(), "listening");
 
-#[derive(serde::Deserialize)]
-struct Params {
-    domain: Option<String>,
-    page:   Option<String>,
-}
+    // On a signal the server stops accepting connections and waits for requests in
+    // progress, but no longer than the shutdown timeout
+    let (signalled_tx, signalled_rx) = oneshot::channel();
+    let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            let _ = signalled_tx.send(());
+        });
+    let mut server = tokio::spawn(server.into_future());
+    let served = tokio::select! {
+        served = &mut server => served,
+        Ok(()) = signalled_rx => {
+            tracing::info!(timeout_secs = config.shutdown_timeout.as_secs(), "shutting down, waiting for requests in progress");
+            match tokio::time::timeout(config.shutdown_timeout, &mut server).await {
+                Ok(served) => served,
+                Err(_) => {
+                    tracing::warn!(timeout_secs = config.shutdown_timeout.as_secs(), "requests still in progress after timeout, closing them");
+                    server.abort();
+                    Ok(Ok(()))
+                }
+            }
+        }
+    };
 
-async fn count_page_view(
-    State(state):  State<AppState>,
-    Query(params): Query<Params>,
-) -> impl IntoResponse {
-    let domain = params.domain.unwrap_or_else(|| "unknown".into());
-    let page = params.page.unwrap_or_else(|| "/unknown".into());
-    let date = OffsetDateTime::now_utc().date();
-    let date_str = format!("{:04}-{:02}-{:02}", date.year(), date.month() as u8, date.day());
-
-    let db = state.db.lock().unwrap();
-    let _ = db.execute(
-        "INSERT INTO pageviews (domain, page, date, view_count) VALUES (?, ?, ?, 1)
-         ON CONFLICT (domain, page, date) DO UPDATE SET view_count = view_count + 1",
-        (domain, page, date_str),
+    // Write the hits still buffered, then move the WAL into the database file so
+    // that it is complete on its own while the service is stopped
+    writer.stop();
+    let _ = flusher.await;
+    let checkpoint = db.lock().unwrap_or_else(|e| e.into_inner()).query_row(
+        "PRAGMA wal_checkpoint(TRUNCATE)",
+        [],
+        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?)),
     );
+    match checkpoint {
+        // Another process, such as a `sites` command, was reading or writing
+        Ok((busy, log_frames, checkpointed)) if busy != 0 => {
+            tracing::warn!(log_frames, checkpointed, "database busy, write-ahead log not fully checkpointed");
+        }
+        Ok(_) => {}
+        Err(e) => tracing::error!(error = %e, "cannot checkpoint database"),
+    }
 
-    (
-        [("Content-Type", "image/gif")],
-        PIXEL_GIF
-    )
+    match served {
+        Ok(result) => result.map_err(|e| format!("server error: {e}")),
+        Err(e) => Err(format!("server error: {e}")),
+    }
 }
 
-async fn export(
-    State(state): State<AppState>,
-    Query(params): Query<Params>,
-) -> impl IntoResponse {
-
-    let db = state.db.lock().unwrap();
-
-    // Fetch pageview records, optionally filtered by domain
-    let (query, params_vec): (&str, Vec<&dyn rusqlite::ToSql>) = if let Some(ref domain) = params.domain {
-        ("SELECT domain, page, date, view_count FROM pageviews WHERE domain = ? ORDER BY date DESC", vec![domain])
-    } else {
-        ("SELECT domain, page, date, view_count FROM pageviews ORDER BY date DESC", vec![])
+/// Completes on Ctrl-C or, on Unix, SIGTERM, which is what fly.io and most
+/// service managers send to stop a process.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => { signal.recv().await; }
+            Err(e) => {
+                tracing::error!(error = %e, "cannot listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
     };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    let mut stmt = db.prepare(query).unwrap();
-    let rows = stmt.query_map(params_vec.as_slice(), |row| {
-        Ok((
-            row.get::<_, String>(0)?,
-            row.get::<_, String>(1)?,
-            row.get::<_, String>(2)?,
-            row.get::<_, i64>(3)?
-        ))
-    }).unwrap();
-
-    let mut pageviews = Vec::new();
-    let mut pages = std::collections::HashSet::new();
-    let mut total_views = 0i64;
-
-    for row in rows {
-        let (domain, page, date, view_count) = row.unwrap();
-        pages.insert(page.clone());
-        total_views += view_count;
-
-        pageviews.push(serde_json::json!({
-            "domain": domain,
-            "page": page,
-            "date": date,
-            "view_count": view_count
-        }));
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
     }
+}
 
-    let summary = serde_json::json!({
-        "unique_pages": pages.len(),
-        "total_views": total_views,
-        "total_records": pageviews.len()
-    });
+/// Query string of `/counter.gif`, and body of `POST /api/hit`.
+#[derive(Default, serde::Deserialize)]
+struct Params {
+    domain: Option<String>,
+    page:   Option<String>,
+    /// The embedding page's `document.referrer`
+    #[serde(rename = "ref")]
+    referrer: Option<String>,
+}
 
-    let result = serde_json::json!({
-        "summary": summary,
-        "pageviews": pageviews
-    });
+/// Always answers with the GIF, so that a page never shows a broken image, even
+/// when the query string is malformed or the hit cannot be recorded.
+async fn count_page_view(
+    State(state):      State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers:           HeaderMap,
+    params:            Result<Query<Params>, QueryRejection>,
+) -> impl IntoResponse {
+    // Falls back to inferring domain and page from the `Referer` header
+    let params = params.map(|Query(params)| params).unwrap_or_default();
+    hits::contain("hit", async move {
+        hits::record(&state, hits::Hit {
+            domain:   params.domain,
+            page:     params.page,
+            referrer: params.referrer.as_deref(),
+            headers:  &headers,
+            peer,
+        }).await;
+    }).await;
 
     (
-        [("Content-Type", "application/json")],
-        serde_json::to_string_pretty(&result).unwrap()
+        [("Content-Type", "image/gif")],
+        PIXEL_GIF
     )
 }
@@ -0,0 +1,161 @@
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::AppState;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Counters and histograms exposed at `/metrics` in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    pixel_hits:     AtomicU64,
+    events:         AtomicU64,
+    dropped_hits:   AtomicU64,
+    write_failures: AtomicU64,
+    write_latency:  Histogram,
+    /// Latency of the stats endpoints, by route.
+    stats_latency:  Mutex<BTreeMap<String, Histogram>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative; the last one is `+Inf`.
+    buckets:    [AtomicU64; BUCKETS.len() + 1],
+    count:      AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = BUCKETS.iter().position(|&le| seconds <= le).unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Appends the `_bucket`, `_sum` and `_count` series; `labels` are prepended to `le`.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = BUCKETS.get(i).map_or("+Inf".to_string(), |le| le.to_string());
+            let _ = writeln!(out, "{name}_bucket{{{labels}le=\"{le}\"}} {cumulative}");
+        }
+        let braces = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels.trim_end_matches(',')) };
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_sum{braces} {sum}");
+        let _ = writeln!(out, "{name}_count{braces} {}", self.count.load(Ordering::Relaxed));
+    }
+}
+
+impl Metrics {
+    pub fn pixel_hit(&self) {
+        self.pixel_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an event received by `/event`.
+    pub fn event(&self) {
+        self.events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records hits that were dropped because too many could not be written.
+    pub fn drop_hits(&self, hits: usize) {
+        self.dropped_hits.fetch_add(hits as u64, Ordering::Relaxed);
+    }
+
+    /// Records a write of buffered hits that took `elapsed`.
+    pub fn write(&self, elapsed: Duration, succeeded: bool) {
+        self.write_latency.observe(elapsed);
+        if !succeeded {
+            self.write_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Middleware timing the stats endpoints, from the request until the response
+/// starts; exports may take longer to stream.
+pub async fn time_stats(
+    State(state): State<AppState>,
+    path: MatchedPath,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let mut latency = state.metrics.stats_latency.lock().unwrap_or_else(|e| e.into_inner());
+    latency.entry(path.as_str().to_string()).or_default().observe(started.elapsed());
+    response
+}
+
+/// `GET /metrics`
+pub async fn metrics(State(state): State<AppState>) -> Response {
+    let metrics = &state.metrics;
+    let mut out = String::new();
+
+    out.push_str("# HELP pixelpagecount_pixel_hits_total Hits received by the counting endpoints.\n");
+    out.push_str("# TYPE pixelpagecount_pixel_hits_total counter\n");
+    let _ = writeln!(out, "pixelpagecount_pixel_hits_total {}", metrics.pixel_hits.load(Ordering::Relaxed));
+
+    out.push_str("# HELP pixelpagecount_events_total Events received by /event.\n");
+    out.push_str("# TYPE pixelpagecount_events_total counter\n");
+    let _ = writeln!(out, "pixelpagecount_events_total {}", metrics.events.load(Ordering::Relaxed));
+
+    out.push_str("# HELP pixelpagecount_buffered_hits Hits counted in memory but not yet written to the database.\n");
+    out.push_str("# TYPE pixelpagecount_buffered_hits gauge\n");
+    let _ = writeln!(out, "pixelpagecount_buffered_hits {}", state.writer.buffered_hits());
+
+    out.push_str("# HELP pixelpagecount_dropped_hits_total Hits dropped because too many could not be written to the database.\n");
+    out.push_str("# TYPE pixelpagecount_dropped_hits_total counter\n");
+    let _ = writeln!(out, "pixelpagecount_dropped_hits_total {}", metrics.dropped_hits.load(Ordering::Relaxed));
+
+    out.push_str("# HELP pixelpagecount_db_write_failures_total Writes of buffered hits to the database that failed.\n");
+    out.push_str("# TYPE pixelpagecount_db_write_failures_total counter\n");
+    let _ = writeln!(out, "pixelpagecount_db_write_failures_total {}", metrics.write_failures.load(Ordering::Relaxed));
+
+    out.push_str("# HELP pixelpagecount_db_write_seconds Time taken to write a batch of buffered hits.\n");
+    out.push_str("# TYPE pixelpagecount_db_write_seconds histogram\n");
+    metrics.write_latency.render(&mut out, "pixelpagecount_db_write_seconds", "");
+
+    out.push_str("# HELP pixelpagecount_stats_request_seconds Time taken to answer stats and dashboard requests.\n");
+    out.push_str("# TYPE pixelpagecount_stats_request_seconds histogram\n");
+    for (route, histogram) in metrics.stats_latency.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        let labels = format!("route=\"{}\",", route.replace('\\', "\\\\").replace('"', "\\\""));
+        histogram.render(&mut out, "pixelpagecount_stats_request_seconds", &labels);
+    }
+
+    let rows = state.reads.run(|db| db.query_row("SELECT COUNT(*) FROM pageviews", [], |row| row.get::<_, i64>(0))).await;
+    match rows {
+        Ok(rows) => {
+            out.push_str("# HELP pixelpagecount_pageviews_rows Rows in the pageviews table.\n");
+            out.push_str("# TYPE pixelpagecount_pageviews_rows gauge\n");
+            let _ = writeln!(out, "pixelpagecount_pageviews_rows {rows}");
+        }
+        Err(e) => tracing::error!(error = %e, "cannot count pageviews rows"),
+    }
+
+    out.push_str("# HELP pixelpagecount_db_size_bytes Size of the database file and its write-ahead log.\n");
+    out.push_str("# TYPE pixelpagecount_db_size_bytes gauge\n");
+    let db_path = &state.config.db_path;
+    let mut wal_path = db_path.clone().into_os_string();
+    wal_path.push("-wal");
+    for (file, path) in [("db", db_path.as_os_str()), ("wal", wal_path.as_os_str())] {
+        let size = std::fs::metadata(path).map_or(0, |m| m.len());
+        let _ = writeln!(out, "pixelpagecount_db_size_bytes{{file=\"{file}\"}} {size}");
+    }
+
+    ([("Content-Type", "text/plain; version=0.0.4; charset=utf-8")], out).into_response()
+}
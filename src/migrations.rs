@@ -0,0 +1,284 @@
+use rusqlite::Connection;
+
+const USAGE: &str = "\
+Usage: pixelpagecount [OPTIONS] migrate [--dry-run]
+
+Upgrades the database schema to the version this build expects. The server and
+the other commands do this on startup; with --dry-run, only lists the migrations
+that would be applied.";
+
+/// One step in the evolution of the database schema.
+pub struct Migration {
+    /// Stored in `PRAGMA user_version` once applied; migrations run in order of version.
+    pub version:     u32,
+    pub description: &'static str,
+    steps:           &'static [Step],
+}
+
+enum Step {
+    Sql(&'static str),
+    /// Adds a column unless the table already has it.
+    AddColumn { table: &'static str, column: &'static str, definition: &'static str },
+}
+
+/// Every migration, in order. Never edit a released migration; add a new one.
+///
+/// Databases created before versioning have `user_version` 0 but may already hold
+/// any of the tables of migrations 1 to 7, so those only create what is missing.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create pageviews",
+        steps: &[Step::Sql("
+            CREATE TABLE IF NOT EXISTS pageviews (
+                domain TEXT NOT NULL,
+                page TEXT NOT NULL,
+                date TEXT NOT NULL,
+                view_count INTEGER NOT NULL DEFAULT 1,
+                PRIMARY KEY (domain, page, date)
+            );")],
+    },
+    Migration {
+        version: 2,
+        description: "create referrers",
+        steps: &[Step::Sql("
+            CREATE TABLE IF NOT EXISTS referrers (
+                domain TEXT NOT NULL,
+                page TEXT NOT NULL,
+                date TEXT NOT NULL,
+                referrer TEXT NOT NULL,
+                view_count INTEGER NOT NULL DEFAULT 1,
+                PRIMARY KEY (domain, page, date, referrer)
+            );")],
+    },
+    Migration {
+        version: 3,
+        description: "create sites and unregistered_hits",
+        steps: &[Step::Sql("
+            CREATE TABLE IF NOT EXISTS sites (
+                domain TEXT NOT NULL PRIMARY KEY COLLATE NOCASE,
+                check_referer INTEGER NOT NULL DEFAULT 0,
+                created TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS unregistered_hits (
+                domain TEXT NOT NULL,
+                date TEXT NOT NULL,
+                hit_count INTEGER NOT NULL DEFAULT 1,
+                PRIMARY KEY (domain, date)
+            );")],
+    },
+    Migration {
+        version: 4,
+        description: "create bot_hits",
+        steps: &[Step::Sql("
+            CREATE TABLE IF NOT EXISTS bot_hits (
+                domain TEXT NOT NULL,
+                page TEXT NOT NULL,
+                date TEXT NOT NULL,
+                bot TEXT NOT NULL,
+                hit_count INTEGER NOT NULL DEFAULT 1,
+                PRIMARY KEY (domain, page, date, bot)
+            );")],
+    },
+    Migration {
+        version: 5,
+        description: "create visitors and site_visitors",
+        steps: &[Step::Sql("
+            CREATE TABLE IF NOT EXISTS visitors (
+                domain TEXT NOT NULL,
+                page TEXT NOT NULL,
+                date TEXT NOT NULL,
+                visitor_count INTEGER NOT NULL DEFAULT 1,
+                PRIMARY KEY (domain, page, date)
+            );
+            CREATE TABLE IF NOT EXISTS site_visitors (
+                domain TEXT NOT NULL,
+                date TEXT NOT NULL,
+                visitor_count INTEGER NOT NULL DEFAULT 1,
+                PRIMARY KEY (domain, date)
+            );")],
+    },
+    Migration {
+        version: 6,
+        description: "create tokens",
+        steps: &[Step::Sql("
+            CREATE TABLE IF NOT EXISTS tokens (
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL DEFAULT '',
+                token_hash TEXT NOT NULL UNIQUE,
+                domain TEXT COLLATE NOCASE,
+                scope TEXT NOT NULL,
+                created TEXT NOT NULL,
+                revoked TEXT
+            );")],
+    },
+    Migration {
+        version: 7,
+        description: "add sites.public and create share_links",
+        steps: &[
+            Step::AddColumn { table: "sites", column: "public", definition: "INTEGER NOT NULL DEFAULT 0" },
+            Step::Sql("
+                CREATE TABLE IF NOT EXISTS share_links (
+                    id INTEGER PRIMARY KEY,
+                    token_hash TEXT NOT NULL UNIQUE,
+                    domain TEXT NOT NULL COLLATE NOCASE,
+                    created TEXT NOT NULL,
+                    revoked TEXT
+                );"),
+        ],
+    },
+    Migration {
+        version: 8,
+        description: "create events and event_props",
+        steps: &[Step::Sql("
+            CREATE TABLE events (
+                domain TEXT NOT NULL,
+                page TEXT NOT NULL,
+                date TEXT NOT NULL,
+                name TEXT NOT NULL,
+                event_count INTEGER NOT NULL DEFAULT 1,
+                PRIMARY KEY (domain, page, date, name)
+            );
+            CREATE TABLE event_props (
+                domain TEXT NOT NULL,
+                page TEXT NOT NULL,
+                date TEXT NOT NULL,
+                name TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                event_count INTEGER NOT NULL DEFAULT 1,
+                PRIMARY KEY (domain, page, date, name, key, value)
+            );")],
+    },
+];
+
+/// The schema version this build expects.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+pub fn schema_version(db: &Connection) -> rusqlite::Result<u32> {
+    db.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+/// Migrations not yet applied to `db`. Fails if `db` was migrated by a newer build.
+pub fn pending(db: &Connection) -> Result<Vec<&'static Migration>, String> {
+    let version = schema_version(db).map_err(|e| format!("cannot read schema version: {e}"))?;
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "database schema version {version} is newer than this build supports ({SCHEMA_VERSION}); upgrade pixelpagecount"
+        ));
+    }
+    Ok(MIGRATIONS.iter().filter(|m| m.version > version).collect())
+}
+
+/// Applies pending migrations, each in its own transaction, and returns them.
+pub fn migrate(db: &mut Connection) -> Result<Vec<&'static Migration>, String> {
+    let pending = pending(db)?;
+    for migration in &pending {
+        apply(db, migration)
+            .map_err(|e| format!("migration {} ({}) failed: {e}", migration.version, migration.description))?;
+    }
+    Ok(pending)
+}
+
+fn apply(db: &mut Connection, migration: &Migration) -> rusqlite::Result<()> {
+    let tx = db.transaction()?;
+    for step in migration.steps {
+        match step {
+            Step::Sql(sql) => tx.execute_batch(sql)?,
+            Step::AddColumn { table, column, definition } => {
+                let exists: bool = tx.query_row(
+                    &format!("SELECT COUNT(*) > 0 FROM pragma_table_info('{table}') WHERE name = ?"),
+                    [column],
+                    |row| row.get(0),
+                )?;
+                if !exists {
+                    tx.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"))?;
+                }
+            }
+        }
+    }
+    // The version is part of the database file, so it is committed along with the changes
+    tx.pragma_update(None, "user_version", migration.version)?;
+    tx.commit()
+}
+
+/// Runs the `migrate` subcommand.
+pub fn run_command(db: &mut Connection, args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let dry_run = match args.as_slice() {
+        [] => false,
+        ["--dry-run"] => true,
+        ["help"] => {
+            println!("{USAGE}");
+            return Ok(());
+        }
+        _ => return Err(USAGE.into()),
+    };
+
+    let current = schema_version(db).map_err(|e| format!("cannot read schema version: {e}"))?;
+    let migrations = if dry_run { pending(db)? } else { migrate(db)? };
+    if migrations.is_empty() {
+        println!("Database is up to date at schema version {current}");
+        return Ok(());
+    }
+    for migration in &migrations {
+        let verb = if dry_run { "Would apply" } else { "Applied" };
+        println!("{verb} migration {}: {}", migration.version, migration.description);
+    }
+    if !dry_run {
+        println!("Database migrated from schema version {current} to {SCHEMA_VERSION}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_increase_up_to_schema_version() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, i + 1, "{}", migration.description);
+        }
+        assert_eq!(MIGRATIONS.last().map(|m| m.version), Some(SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn migrates_database_from_before_versioning() {
+        let mut db = Connection::open_in_memory().unwrap();
+        db.execute_batch("
+            CREATE TABLE pageviews (
+                domain TEXT NOT NULL,
+                page TEXT NOT NULL,
+                date TEXT NOT NULL,
+                view_count INTEGER NOT NULL DEFAULT 1,
+                PRIMARY KEY (domain, page, date)
+            );
+            CREATE TABLE sites (
+                domain TEXT NOT NULL PRIMARY KEY COLLATE NOCASE,
+                check_referer INTEGER NOT NULL DEFAULT 0,
+                created TEXT NOT NULL
+            );
+            INSERT INTO pageviews VALUES ('example.com', '/', '2024-05-01', 3);
+            INSERT INTO sites VALUES ('example.com', 1, '2024-04-01');
+        ").unwrap();
+
+        assert_eq!(migrate(&mut db).unwrap().len(), MIGRATIONS.len());
+        assert_eq!(schema_version(&db).unwrap(), SCHEMA_VERSION);
+        let site: (bool, bool) = db.query_row(
+            "SELECT check_referer, public FROM sites WHERE domain = 'example.com'", [], |row| Ok((row.get(0)?, row.get(1)?)),
+        ).unwrap();
+        assert_eq!(site, (true, false));
+        let views: i64 = db.query_row("SELECT view_count FROM pageviews", [], |row| row.get(0)).unwrap();
+        assert_eq!(views, 3);
+
+        assert!(migrate(&mut db).unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_newer_schema() {
+        let db = Connection::open_in_memory().unwrap();
+        db.pragma_update(None, "user_version", SCHEMA_VERSION + 1).unwrap();
+        assert!(pending(&db).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use url::Url;
+
+/// Works out which host sent the visitor, keeping only the host name so that no
+/// full URLs (with paths or query strings that may identify someone) are stored.
+///
+/// `ref_param` is the page's `document.referrer`, passed explicitly by the embedding
+/// page. The `Referer` header of the pixel request itself names the page embedding
+/// the pixel, so it is only used when it points somewhere other than the tracked
+/// domain, as happens when the pixel is embedded on a third-party site.
+/// Referrals from the tracked domain itself are not recorded.
+pub fn referring_host(ref_param: Option<&str>, header: Option<&str>, domain: &str) -> Option<String> {
+    let host = match ref_param.filter(|r| !r.is_empty()) {
+        Some(referrer) => parse_host(referrer)?,
+        None => parse_host(header?)?,
+    };
+    if same_site(&host, domain) {
+        None
+    } else {
+        Some(host)
+    }
+}
+
+/// Extracts the lower-cased host from a URL, or `None` if it has none.
+pub fn parse_host(url: &str) -> Option<String> {
+    parse_page(url).map(|(host, _)| host)
+}
+
+/// Splits an http(s) URL into its lower-cased host and its path, dropping the
+/// query string and fragment.
+pub fn parse_page(url: &str) -> Option<(String, String)> {
+    let url = Url::parse(url).ok()?;
+    match url.scheme() {
+        "http" | "https" => url.host_str().map(|h| (h.to_ascii_lowercase(), url.path().to_string())),
+        _ => None,
+    }
+}
+
+/// Whether `host` is `domain`, ignoring case and a leading `www.`.
+pub fn same_site(host: &str, domain: &str) -> bool {
+    let strip = |h: &str| h.strip_prefix("www.").unwrap_or(h).to_ascii_lowercase();
+    strip(host) == strip(domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_site_ignores_case_and_www() {
+        assert!(same_site("example.com", "example.com"));
+        assert!(same_site("www.example.com", "Example.com"));
+        assert!(same_site("EXAMPLE.COM", "www.example.com"));
+        assert!(!same_site("blog.example.com", "example.com"));
+        assert!(!same_site("example.com.evil.test", "example.com"));
+    }
+
+    #[test]
+    fn parse_page_splits_http_urls() {
+        assert_eq!(
+            parse_page("https://Example.COM/blog/post?utm_source=x#top"),
+            Some(("example.com".to_string(), "/blog/post".to_string())),
+        );
+        assert_eq!(parse_page("http://example.com"), Some(("example.com".to_string(), "/".to_string())));
+        assert_eq!(parse_page("ftp://example.com/file"), None);
+        assert_eq!(parse_page("/relative/path"), None);
+        assert_eq!(parse_page(""), None);
+    }
+
+    #[test]
+    fn referring_host_keeps_only_other_hosts() {
+        let hn = Some("https://news.ycombinator.com/item?id=1");
+        assert_eq!(referring_host(hn, None, "example.com").as_deref(), Some("news.ycombinator.com"));
+        // The explicit referrer wins over the header, which names the embedding page
+        assert_eq!(referring_host(hn, Some("https://other.test/"), "example.com").as_deref(), Some("news.ycombinator.com"));
+        assert_eq!(referring_host(Some(""), Some("https://other.test/"), "example.com").as_deref(), Some("other.test"));
+        assert_eq!(referring_host(None, Some("https://www.example.com/page"), "example.com"), None);
+        assert_eq!(referring_host(Some("not a url"), None, "example.com"), None);
+    }
+}
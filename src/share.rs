@@ -0,0 +1,202 @@
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use rusqlite::{Connection, OptionalExtension};
+use time::OffsetDateTime;
+
+use crate::{
+    auth::{self, Access, Scope},
+    db,
+    error::{AppError, AppResult},
+    extract::Path,
+    sites,
+    stats::format_date,
+    AppState,
+};
+
+/// Prefix of share link tokens, to tell them apart from API tokens.
+const TOKEN_PREFIX: &str = "pps_";
+
+/// A link giving anyone who has it read access to the stats of one domain.
+pub struct ShareLink {
+    pub id:      i64,
+    pub domain:  String,
+    pub created: String,
+}
+
+/// Creates a share link for `domain`, returning its id and token. Only the hash of
+/// the token is stored, so it cannot be shown again.
+pub fn create(db: &Connection, domain: &str) -> Result<(i64, String), String> {
+    if sites::list(db, Some(domain)).map_err(|e| e.to_string())?.is_empty() {
+        return Err(format!("{domain} is not registered"));
+    }
+    let token = auth::generate(TOKEN_PREFIX)?;
+    db.execute(
+        "INSERT INTO share_links (token_hash, domain, created) VALUES (?, ?, ?)",
+        (auth::hash(&token), domain.to_ascii_lowercase(), format_date(OffsetDateTime::now_utc().date())),
+    ).map_err(|e| e.to_string())?;
+    Ok((db.last_insert_rowid(), token))
+}
+
+/// Share links that have not been revoked, or only those of `domain` if given.
+pub fn list(db: &Connection, domain: Option<&str>) -> rusqlite::Result<Vec<ShareLink>> {
+    let mut stmt = db.prepare(
+        "SELECT id, domain, created FROM share_links
+         WHERE revoked IS NULL AND (?1 IS NULL OR domain = ?1) ORDER BY id"
+    )?;
+    let rows = stmt.query_map([domain], |row| {
+        Ok(ShareLink { id: row.get(0)?, domain: row.get(1)?, created: row.get(2)? })
+    })?;
+    rows.collect()
+}
+
+/// Revokes share link `id`, if it belongs to `domain` when given. Returns whether
+/// there was such a link.
+pub fn revoke(db: &Connection, id: i64, domain: Option<&str>) -> rusqlite::Result<bool> {
+    let revoked = db.execute(
+        "UPDATE share_links SET revoked = ?1
+         WHERE id = ?2 AND revoked IS NULL AND (?3 IS NULL OR domain = ?3)",
+        (format_date(OffsetDateTime::now_utc().date()), id, domain),
+    )?;
+    Ok(revoked > 0)
+}
+
+/// The domain of the share link with `token`, unless it is unknown or revoked.
+fn domain_of(db: &Connection, token: &str) -> rusqlite::Result<Option<String>> {
+    db.query_row(
+        "SELECT domain FROM share_links WHERE token_hash = ? AND revoked IS NULL",
+        [auth::hash(token)],
+        |row| row.get(0),
+    ).optional()
+}
+
+/// What a share link for `domain` grants: reading the stats of that domain only.
+fn access(domain: String) -> Access {
+    Access { domain: Some(domain), scope: Scope::Read, public_domains: Vec::new() }
+}
+
+/// Middleware for the routes under `/share/{token}`: gives the request read access
+/// to the link's domain, or answers `404 Not Found` if the link is unknown or revoked.
+pub async fn authorize(
+    State(state): State<AppState>,
+    Path(token):  Path<String>,
+    mut request:  Request,
+    next:         Next,
+) -> Response {
+    let domain = state.reads.run(move |db| domain_of(db, &token)).await;
+    match domain {
+        Ok(Some(domain)) => {
+            request.extensions_mut().insert(access(domain));
+            next.run(request).await
+        }
+        Ok(None) => AppError::not_found("unknown or revoked share link").into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+fn link_json(link: &ShareLink) -> serde_json::Value {
+    serde_json::json!({
+        "id": link.id,
+        "domain": link.domain,
+        "created": link.created
+    })
+}
+
+/// `GET /admin/sites/{domain}/shares`: the domain's active share links.
+pub async fn list_links(
+    State(state): State<AppState>,
+    access: Access,
+    Path(domain): Path<String>,
+) -> AppResult {
+    access.require_admin(Some(&domain))?;
+    let links = state.reads.run(move |db| list(db, Some(&domain))).await?;
+    Ok(Json(serde_json::json!({ "shares": links.iter().map(link_json).collect::<Vec<_>>() })).into_response())
+}
+
+/// `POST /admin/sites/{domain}/shares`: creates a share link. Its URL is only shown once.
+pub async fn create_link(
+    State(state): State<AppState>,
+    access: Access,
+    Path(domain): Path<String>,
+) -> AppResult {
+    access.require_admin(Some(&domain))?;
+    let site = domain.clone();
+    let (id, token) = db::write(&state.db, move |db| {
+        if sites::list(db, Some(&site))?.is_empty() {
+            return Err(AppError::not_found(format!("{site} is not registered")));
+        }
+        create(db, &site).map_err(AppError::Internal)
+    }).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(serde_json::json!({
+            "id": id,
+            "domain": domain.to_ascii_lowercase(),
+            "url": format!("/share/{token}")
+        })),
+    ).into_response())
+}
+
+/// `DELETE /admin/sites/{domain}/shares/{id}`: revokes a share link.
+pub async fn delete_link(
+    State(state): State<AppState>,
+    access: Access,
+    Path((domain, id)): Path<(String, i64)>,
+) -> AppResult {
+    access.require_admin(Some(&domain))?;
+    db::write(&state.db, move |db| {
+        if !revoke(db, id, Some(&domain))? {
+            return Err(AppError::not_found(format!("no active share link {id} for {domain}")));
+        }
+        Ok(())
+    }).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db() -> Connection {
+        let mut db = Connection::open_in_memory().unwrap();
+        crate::migrations::migrate(&mut db).unwrap();
+        sites::add(&db, "example.com", false, false).unwrap();
+        db
+    }
+
+    #[test]
+    fn links_give_their_domain_until_revoked() {
+        let db = db();
+        let (id, token) = create(&db, "Example.com").unwrap();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_eq!(domain_of(&db, &token).unwrap().as_deref(), Some("example.com"));
+        assert_eq!(list(&db, Some("example.com")).unwrap().len(), 1);
+
+        assert!(!revoke(&db, id, Some("other.test")).unwrap());
+        assert!(revoke(&db, id, Some("example.com")).unwrap());
+        assert_eq!(domain_of(&db, &token).unwrap(), None);
+        assert!(!revoke(&db, id, None).unwrap());
+        assert!(list(&db, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_links_give_nothing() {
+        let db = db();
+        create(&db, "example.com").unwrap();
+        assert_eq!(domain_of(&db, "pps_unknown").unwrap(), None);
+        assert!(create(&db, "other.test").is_err());
+    }
+
+    #[test]
+    fn links_only_read_their_domain() {
+        let access = access("example.com".into());
+        let mut domain = None;
+        assert!(access.restrict(&mut domain).is_ok());
+        assert_eq!(domain.as_deref(), Some("example.com"));
+        assert!(access.restrict(&mut Some("other.test".into())).is_err());
+        assert!(access.require_admin(Some("example.com")).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json},
+};
+use rusqlite::Connection;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use time::OffsetDateTime;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    auth::Access,
+    config::Unregistered,
+    db::{self, ReadPool},
+    error::{AppError, AppResult},
+    extract::{Path, Query},
+    referrer,
+    share,
+    stats::format_date,
+    AppState,
+};
+
+/// How often the registry is reloaded from the database, for changes made with
+/// the `sites` command while the server runs.
+const REGISTRY_REFRESH: Duration = Duration::from_secs(30);
+
+const USAGE: &str = "\
+Usage: pixelpagecount [OPTIONS] sites <COMMAND>
+
+Commands:
+  list                                List registered sites
+  add <DOMAIN> [--check-referer] [--public]
+                                      Register a site, or change its settings; with
+                                      --check-referer, hits whose Referer header names
+                                      another host are not counted; with --public, its
+                                      stats can be read without an API token
+  remove <DOMAIN>                     Unregister a site (its recorded page views are kept)
+  unregistered                        List hits for unregistered domains, as recorded with
+                                      --unregistered-sites log
+  share <DOMAIN>                      Create a link showing the stats of one domain
+                                      to anyone who has it
+  shares [<DOMAIN>]                   List share links
+  unshare <ID>                        Revoke a share link";
+
+/// What to do with a hit for `domain`.
+pub enum Verdict {
+    Count,
+    Drop,
+    /// Not counted, but noted in `unregistered_hits`.
+    Log,
+}
+
+pub struct Site {
+    pub domain:        String,
+    pub check_referer: bool,
+    /// Whether anyone may read the site's stats, without an API token.
+    pub public:        bool,
+    pub created:       String,
+}
+
+/// Registered sites, or only `domain` if given.
+pub fn list(db: &Connection, domain: Option<&str>) -> rusqlite::Result<Vec<Site>> {
+    let mut stmt = db.prepare(
+        "SELECT domain, check_referer, public, created FROM sites WHERE ?1 IS NULL OR domain = ?1 ORDER BY domain"
+    )?;
+    let rows = stmt.query_map([domain], |row| {
+        Ok(Site { domain: row.get(0)?, check_referer: row.get(1)?, public: row.get(2)?, created: row.get(3)? })
+    })?;
+    rows.collect()
+}
+
+/// The registered sites, kept in memory so that counting a hit does not wait for
+/// a database connection. Reloaded when sites change through the admin API, and
+/// every `REGISTRY_REFRESH` to pick up changes made with the `sites` command.
+#[derive(Default)]
+pub struct Registry {
+    /// Keyed by lowercase domain, as domains are compared ignoring case.
+    sites: RwLock<HashMap<String, Settings>>,
+}
+
+struct Settings {
+    /// As registered, for filtering stats.
+    domain:        String,
+    check_referer: bool,
+    public:        bool,
+}
+
+impl Registry {
+    pub fn load(db: &Connection) -> rusqlite::Result<Registry> {
+        let registry = Registry::default();
+        registry.reload(db)?;
+        Ok(registry)
+    }
+
+    /// Replaces the sites with those in the database.
+    pub fn reload(&self, db: &Connection) -> rusqlite::Result<()> {
+        let sites = list(db, None)?.into_iter()
+            .map(|site| (
+                site.domain.to_ascii_lowercase(),
+                Settings { domain: site.domain, check_referer: site.check_referer, public: site.public },
+            ))
+            .collect();
+        *self.sites.write().unwrap_or_else(|e| e.into_inner()) = sites;
+        Ok(())
+    }
+
+    /// Decides whether a hit claiming to be for `domain` should be counted. Hits for
+    /// unregistered domains are handled as `mode` says.
+    pub fn check(&self, mode: Unregistered, domain: &str, referer: Option<&str>) -> Verdict {
+        let check_referer = self.sites.read().unwrap_or_else(|e| e.into_inner())
+            .get(&domain.to_ascii_lowercase())
+            .map(|settings| settings.check_referer);
+        match check_referer {
+            // Browsers may leave out Referer entirely, so only a mismatching host is rejected
+            Some(true) => match referer.and_then(referrer::parse_host) {
+                Some(host) if !referrer::same_site(&host, domain) => Verdict::Drop,
+                _ => Verdict::Count,
+            },
+            Some(false) => Verdict::Count,
+            None => match mode {
+                Unregistered::Count => Verdict::Count,
+                Unregistered::Drop => Verdict::Drop,
+                Unregistered::Log => Verdict::Log,
+            },
+        }
+    }
+
+    /// Whether `host`, e.g. of an `Origin` header, is a registered site, ignoring
+    /// a leading `www.` on either.
+    pub fn is_registered_host(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        let bare = host.strip_prefix("www.").unwrap_or(&host);
+        let sites = self.sites.read().unwrap_or_else(|e| e.into_inner());
+        sites.contains_key(bare) || sites.contains_key(&format!("www.{bare}"))
+    }
+
+    /// Domains of the sites whose stats are public.
+    pub fn public_domains(&self) -> Vec<String> {
+        let sites = self.sites.read().unwrap_or_else(|e| e.into_inner());
+        let mut domains: Vec<String> = sites.values()
+            .filter(|settings| settings.public)
+            .map(|settings| settings.domain.clone())
+            .collect();
+        domains.sort();
+        domains
+    }
+}
+
+/// Reloads `registry` every `REGISTRY_REFRESH`, logging failures.
+pub async fn refresh(registry: Arc<Registry>, reads: Arc<ReadPool>) {
+    let mut interval = tokio::time::interval(REGISTRY_REFRESH);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let registry = registry.clone();
+        if let Err(e) = reads.run(move |db| registry.reload(db)).await {
+            tracing::error!(error = %e, "cannot reload registered sites");
+        }
+    }
+}
+
+/// Registers `domain`, or updates its settings if already registered.
+pub fn add(db: &Connection, domain: &str, check_referer: bool, public: bool) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT INTO sites (domain, check_referer, public, created) VALUES (?, ?, ?, ?)
+         ON CONFLICT (domain) DO UPDATE SET check_referer = excluded.check_referer, public = excluded.public",
+        (domain.to_ascii_lowercase(), check_referer, public, format_date(OffsetDateTime::now_utc().date())),
+    )?;
+    Ok(())
+}
+
+/// Unregisters `domain`, returning whether it was registered.
+pub fn remove(db: &Connection, domain: &str) -> rusqlite::Result<bool> {
+    Ok(db.execute("DELETE FROM sites WHERE domain = ?", [domain])? > 0)
+}
+
+/// Runs a `sites` subcommand.
+pub fn run_command(db: &Connection, args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["list"] => {
+            for site in list(db, None).map_err(|e| e.to_string())? {
+                let check = if site.check_referer { "  check-referer" } else { "" };
+                let public = if site.public { "  public" } else { "" };
+                println!("{}  (added {}){check}{public}", site.domain, site.created);
+            }
+            Ok(())
+        }
+        ["add", domain, flags @ ..] => {
+            let (mut check_referer, mut public) = (false, false);
+            for flag in flags {
+                match *flag {
+                    "--check-referer" => check_referer = true,
+                    "--public" => public = true,
+                    other => return Err(format!("unexpected argument {other}\n\n{USAGE}")),
+                }
+            }
+            add(db, domain, check_referer, public).map_err(|e| e.to_string())?;
+            println!("Registered {}", domain.to_ascii_lowercase());
+            Ok(())
+        }
+        ["remove", domain] => {
+            if !remove(db, domain).map_err(|e| e.to_string())? {
+                return Err(format!("{domain} is not registered"));
+            }
+            println!("Removed {domain}");
+            Ok(())
+        }
+        ["unregistered"] => {
+            let mut stmt = db.prepare(
+                "SELECT domain, SUM(hit_count), MAX(date) FROM unregistered_hits
+                 GROUP BY domain ORDER BY SUM(hit_count) DESC, domain"
+            ).map_err(|e| e.to_string())?;
+            let rows = stmt.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, String>(2)?))
+            }).map_err(|e| e.to_string())?;
+            for row in rows {
+                let (domain, hits, last_seen) = row.map_err(|e| e.to_string())?;
+                println!("{domain}  {hits} hits, last seen {last_seen}");
+            }
+            Ok(())
+        }
+        ["share", domain] => {
+            let (id, token) = share::create(db, domain)?;
+            eprintln!("Created share link {id} for {domain}. Anyone with this link can see its stats:");
+            println!("/share/{token}");
+            Ok(())
+        }
+        ["shares", domain @ ..] if domain.len() <= 1 => {
+            for link in share::list(db, domain.first().copied()).map_err(|e| e.to_string())? {
+                println!("{}  {}  (created {})", link.id, link.domain, link.created);
+            }
+            Ok(())
+        }
+        ["unshare", id] => {
+            let id: i64 = id.parse().map_err(|_| format!("invalid share link id '{id}'"))?;
+            if !share::revoke(db, id, None).map_err(|e| e.to_string())? {
+                return Err(format!("no active share link with id {id}"));
+            }
+            println!("Revoked share link {id}");
+            Ok(())
+        }
+        ["help"] => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        _ => Err(USAGE.into()),
+    }
+}
+
+fn site_json(site: &Site) -> serde_json::Value {
+    serde_json::json!({
+        "domain": site.domain,
+        "check_referer": site.check_referer,
+        "public": site.public,
+        "created": site.created
+    })
+}
+
+/// `GET /admin/sites`: the registered sites the token may administer.
+pub async fn list_sites(State(state): State<AppState>, access: Access) -> AppResult {
+    access.require_admin(access.domain.as_deref())?;
+    let sites = state.reads.run(move |db| list(db, access.domain.as_deref())).await?;
+    Ok(Json(serde_json::json!({ "sites": sites.iter().map(site_json).collect::<Vec<_>>() })).into_response())
+}
+
+#[derive(serde::Deserialize)]
+pub struct SiteParams {
+    #[serde(default)]
+    check_referer: bool,
+    #[serde(default)]
+    public: bool,
+}
+
+/// `PUT /admin/sites/{domain}`: registers a site, or updates its settings.
+pub async fn put_site(
+    State(state): State<AppState>,
+    access: Access,
+    Path(domain): Path<String>,
+    Query(params): Query<SiteParams>,
+) -> AppResult {
+    access.require_admin(Some(&domain))?;
+    let registry = state.sites.clone();
+    let sites = db::write(&state.db, move |db| {
+        add(db, &domain, params.check_referer, params.public)?;
+        registry.reload(db)?;
+        list(db, Some(&domain))
+    }).await?;
+    Ok(Json(serde_json::json!({ "sites": sites.iter().map(site_json).collect::<Vec<_>>() })).into_response())
+}
+
+/// `DELETE /admin/sites/{domain}`: unregisters a site, keeping its recorded data.
+pub async fn delete_site(
+    State(state): State<AppState>,
+    access: Access,
+    Path(domain): Path<String>,
+) -> AppResult {
+    access.require_admin(Some(&domain))?;
+    let registry = state.sites.clone();
+    db::write(&state.db, move |db| {
+        if !remove(db, &domain)? {
+            return Err(AppError::not_found(format!("{domain} is not registered")));
+        }
+        registry.reload(db)?;
+        Ok(())
+    }).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db() -> Connection {
+        let mut db = Connection::open_in_memory().unwrap();
+        crate::migrations::migrate(&mut db).unwrap();
+        add(&db, "example.com", true, false).unwrap();
+        add(&db, "blog.test", false, true).unwrap();
+        db
+    }
+
+    #[test]
+    fn registry_checks_sites() {
+        let registry = Registry::load(&db()).unwrap();
+        let check = |mode, domain, referer| registry.check(mode, domain, referer);
+
+        assert!(matches!(check(Unregistered::Drop, "example.com", Some("https://www.example.com/")), Verdict::Count));
+        assert!(matches!(check(Unregistered::Drop, "example.com", None), Verdict::Count));
+        assert!(matches!(check(Unregistered::Drop, "example.com", Some("https://evil.test/")), Verdict::Drop));
+        assert!(matches!(check(Unregistered::Drop, "blog.test", Some("https://evil.test/")), Verdict::Count));
+        assert!(matches!(check(Unregistered::Drop, "other.test", None), Verdict::Drop));
+        assert!(matches!(check(Unregistered::Log, "other.test", None), Verdict::Log));
+        assert!(matches!(check(Unregistered::Count, "other.test", None), Verdict::Count));
+        assert!(matches!(check(Unregistered::Count, "example.com", Some("https://evil.test/")), Verdict::Drop));
+        assert!(matches!(check(Unregistered::Count, "example.com", Some("https://example.com/")), Verdict::Count));
+        assert_eq!(registry.public_domains(), ["blog.test"]);
+    }
+
+    #[test]
+    fn registry_ignores_case_and_www() {
+        let db = db();
+        db.execute("INSERT INTO sites (domain, created) VALUES ('www.Shop.test', '2024-01-01')", []).unwrap();
+        let registry = Registry::load(&db).unwrap();
+
+        assert!(matches!(registry.check(Unregistered::Drop, "EXAMPLE.com", None), Verdict::Count));
+        assert!(registry.is_registered_host("example.com"));
+        assert!(registry.is_registered_host("WWW.Example.com"));
+        assert!(registry.is_registered_host("shop.test"));
+        assert!(registry.is_registered_host("www.shop.test"));
+        assert!(!registry.is_registered_host("blog.example.com"));
+        assert!(!registry.is_registered_host("example.com.evil.test"));
+    }
+
+    #[test]
+    fn registry_reloads() {
+        let db = db();
+        let registry = Registry::load(&db).unwrap();
+        remove(&db, "blog.test").unwrap();
+        add(&db, "new.test", false, true).unwrap();
+        assert_eq!(registry.public_domains(), ["blog.test"]);
+
+        registry.reload(&db).unwrap();
+        assert_eq!(registry.public_domains(), ["new.test"]);
+        assert!(matches!(registry.check(Unregistered::Drop, "blog.test", None), Verdict::Drop));
+    }
+}
@@ -0,0 +1,660 @@
+use axum::{
+    extract::{OriginalUri, State},
+    http::Uri,
+    response::IntoResponse,
+};
+use rusqlite::ToSql;
+use time::{macros::format_description, Date, Duration, OffsetDateTime};
+
+use crate::{auth::Access, error::{AppError, AppResult}, export, extract::Query, AppState};
+
+/// Number of rows returned when no `limit` is given.
+const DEFAULT_LIMIT: u64 = 1000;
+/// Upper bound for `limit`; larger values are clamped to this.
+const MAX_LIMIT: u64 = 10_000;
+
+/// Query string accepted by the stats endpoints.
+#[derive(serde::Deserialize)]
+pub struct StatsParams {
+    domain:      Option<String>,
+    page:        Option<String>,
+    page_prefix: Option<String>,
+    from:        Option<String>,
+    to:          Option<String>,
+    group_by:    Option<String>,
+    limit:       Option<String>,
+    offset:      Option<String>,
+    format:      Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum GroupBy {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl GroupBy {
+    fn parse(s: &str) -> Result<GroupBy, String> {
+        match s {
+            "day"   => Ok(GroupBy::Day),
+            "week"  => Ok(GroupBy::Week),
+            "month" => Ok(GroupBy::Month),
+            "year"  => Ok(GroupBy::Year),
+            _ => Err(format!("invalid group_by '{s}', expected day, week, month or year")),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GroupBy::Day   => "day",
+            GroupBy::Week  => "week",
+            GroupBy::Month => "month",
+            GroupBy::Year  => "year",
+        }
+    }
+
+    /// SQL expression mapping `date` to the first day of its period.
+    /// Weeks start on Monday.
+    fn period_sql(self) -> &'static str {
+        match self {
+            GroupBy::Day   => "date",
+            GroupBy::Week  => "date(date, 'weekday 0', '-6 days')",
+            GroupBy::Month => "substr(date, 1, 7) || '-01'",
+            GroupBy::Year  => "substr(date, 1, 4) || '-01-01'",
+        }
+    }
+}
+
+/// Validated filters shared by the stats endpoints.
+pub struct Filter {
+    pub domain:      Option<String>,
+    pub page:        Option<String>,
+    pub page_prefix: Option<String>,
+    pub from:        Option<Date>,
+    pub to:          Option<Date>,
+    pub group_by:    GroupBy,
+    /// As requested, before applying defaults and bounds; see `page_limit`.
+    pub limit:       Option<u64>,
+    pub offset:      u64,
+}
+
+impl Filter {
+    pub fn from_params(params: StatsParams) -> Result<Filter, String> {
+        let from = params.from.as_deref().map(|d| parse_date("from", d)).transpose()?;
+        let to = params.to.as_deref().map(|d| parse_date("to", d)).transpose()?;
+        if let (Some(from), Some(to)) = (from, to) {
+            if from > to {
+                return Err(format!("from ({from}) is after to ({to})"));
+            }
+        }
+        let group_by = params.group_by.as_deref().map(GroupBy::parse).transpose()?.unwrap_or(GroupBy::Day);
+        let limit = params.limit.as_deref().map(|l| parse_count("limit", l)).transpose()?;
+        let offset = params.offset.as_deref().map(|o| parse_count("offset", o)).transpose()?.unwrap_or(0);
+
+        Ok(Filter {
+            domain: params.domain,
+            page: params.page,
+            page_prefix: params.page_prefix,
+            from,
+            to,
+            group_by,
+            limit,
+            offset,
+        })
+    }
+
+    /// Number of rows to return in one response of a paginated endpoint.
+    pub fn page_limit(&self) -> u64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+
+    /// Query returning `domain`, `page`, `period`, `view_count` and `visitors` for
+    /// every record matching `where_clause`, summed per period, in no particular order.
+    pub fn records_sql(&self, where_clause: &str) -> String {
+        format!(
+            "SELECT domain, page, {period} AS period, SUM(view_count) AS view_count,
+                    SUM(COALESCE(visitor_count, 0)) AS visitors
+             FROM pageviews LEFT JOIN visitors USING (domain, page, date) {where_clause}
+             GROUP BY domain, page, period",
+            period = self.group_by.period_sql(),
+        )
+    }
+
+    /// Builds a `WHERE ...` clause (or an empty string) and its bound parameters.
+    pub fn where_clause(&self) -> (String, Vec<Box<dyn ToSql + Send>>) {
+        let mut conditions = Vec::new();
+        let mut params: Vec<Box<dyn ToSql + Send>> = Vec::new();
+
+        if let Some(ref domain) = self.domain {
+            conditions.push("domain = ?");
+            params.push(Box::new(domain.clone()));
+        }
+        if let Some(ref page) = self.page {
+            conditions.push("page = ?");
+            params.push(Box::new(page.clone()));
+        }
+        if let Some(ref prefix) = self.page_prefix {
+            // substr rather than LIKE, so that '%' and '_' in paths need no escaping
+            conditions.push("substr(page, 1, ?) = ?");
+            params.push(Box::new(prefix.chars().count() as i64));
+            params.push(Box::new(prefix.clone()));
+        }
+        if let Some(from) = self.from {
+            conditions.push("date >= ?");
+            params.push(Box::new(format_date(from)));
+        }
+        if let Some(to) = self.to {
+            conditions.push("date <= ?");
+            params.push(Box::new(format_date(to)));
+        }
+
+        let clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        (clause, params)
+    }
+}
+
+pub fn parse_date(name: &str, value: &str) -> Result<Date, String> {
+    Date::parse(value, format_description!("[year]-[month]-[day]"))
+        .map_err(|_| format!("invalid {name} date '{value}', expected YYYY-MM-DD"))
+}
+
+/// Parses a limit or offset, which SQLite takes as a signed 64-bit integer.
+fn parse_count(name: &str, value: &str) -> Result<u64, String> {
+    value.parse().ok()
+        .filter(|&n| n <= i64::MAX as u64)
+        .ok_or_else(|| format!("invalid {name} '{value}', expected a non-negative integer up to {}", i64::MAX))
+}
+
+/// `params` followed by `limit` and `offset`, for a query ending in `LIMIT ? OFFSET ?`.
+pub fn paged<'a>(params: &[&'a dyn ToSql], limit: &'a i64, offset: &'a i64) -> Vec<&'a dyn ToSql> {
+    params.iter().copied().chain([limit as &dyn ToSql, offset]).collect()
+}
+
+/// Link to the page following the current one: the request URI with `offset` replaced.
+fn next_link(uri: &Uri, offset: u64) -> String {
+    let mut query: Vec<&str> = uri.query().unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty() && pair.split('=').next() != Some("offset"))
+        .collect();
+    let offset = format!("offset={offset}");
+    query.push(&offset);
+    format!("{}?{}", uri.path(), query.join("&"))
+}
+
+pub fn format_date(date: Date) -> String {
+    format!("{:04}-{:02}-{:02}", date.year(), date.month() as u8, date.day())
+}
+
+pub async fn export(
+    State(state): State<AppState>,
+    access: Access,
+    OriginalUri(uri): OriginalUri,
+    Query(params): Query<StatsParams>,
+) -> AppResult {
+    let format = params.format.as_deref().map(export::Format::parse).transpose().map_err(AppError::BadRequest)?;
+    let mut filter = Filter::from_params(params).map_err(AppError::BadRequest)?;
+    access.restrict(&mut filter.domain)?;
+    if let Some(format) = format {
+        return Ok(export::stream(state, filter, format).await);
+    }
+    let limit = filter.page_limit();
+
+    state.reads.run(move |db| {
+        // Fetch pageview records matching the filter, summed per period
+        let (where_clause, params_vec) = filter.where_clause();
+        let grouped = filter.records_sql(&where_clause);
+        let params_refs: Vec<&dyn ToSql> = params_vec.iter().map(|p| p.as_ref() as &dyn ToSql).collect();
+
+        // Totals cover every matching record, not only the current page
+        let (total_records, total_views, unique_pages, page_visitors) = db.query_row(
+            &format!("SELECT COUNT(*), COALESCE(SUM(view_count), 0), COUNT(DISTINCT page), COALESCE(SUM(visitors), 0) FROM ({grouped})"),
+            params_refs.as_slice(),
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?, row.get::<_, i64>(3)?)),
+        )?;
+
+        // A visitor seeing several pages on a day counts once for the site, so use the
+        // site-wide counts unless the filter picks out individual pages
+        let visitors = if filter.page.is_none() && filter.page_prefix.is_none() {
+            db.query_row(
+                &format!("SELECT COALESCE(SUM(visitor_count), 0) FROM site_visitors {where_clause}"),
+                params_refs.as_slice(),
+                |row| row.get::<_, i64>(0),
+            )?
+        } else {
+            page_visitors
+        };
+
+        // One row more than requested tells whether there is a next page
+        let (page_limit, offset) = (limit as i64 + 1, filter.offset as i64);
+        let mut stmt = db.prepare(&format!("{grouped} ORDER BY period DESC, domain, page LIMIT ? OFFSET ?"))?;
+        let rows = stmt.query_map(paged(&params_refs, &page_limit, &offset).as_slice(), |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, i64>(4)?
+            ))
+        })?;
+
+        let mut pageviews = Vec::new();
+        for row in rows {
+            let (domain, page, date, view_count, visitors) = row?;
+            pageviews.push(serde_json::json!({
+                "domain": domain,
+                "page": page,
+                "date": date,
+                "view_count": view_count,
+                "visitors": visitors
+            }));
+        }
+
+        let has_more = pageviews.len() as u64 > limit;
+        pageviews.truncate(limit as usize);
+        let next = has_more.then(|| next_link(&uri, filter.offset + limit));
+
+        let summary = serde_json::json!({
+            "unique_pages": unique_pages,
+            "total_views": total_views,
+            "visitors": visitors,
+            "total_records": total_records,
+            "group_by": filter.group_by.as_str()
+        });
+
+        let pagination = serde_json::json!({
+            "limit": limit,
+            "offset": filter.offset,
+            "next": next
+        });
+
+        let result = serde_json::json!({
+            "summary": summary,
+            "pagination": pagination,
+            "pageviews": pageviews
+        });
+
+        Ok::<_, AppError>((
+            [("Content-Type", "application/json")],
+            serde_json::to_string_pretty(&result)?
+        ).into_response())
+    }).await
+}
+
+/// Number of pages returned by `/stats/top.json` when no `limit` is given.
+const DEFAULT_TOP_LIMIT: u64 = 10;
+/// Upper bound for `limit` on `/stats/top.json`.
+const MAX_TOP_LIMIT: u64 = 100;
+/// Length of the period used by `/stats/top.json` when `from` is not given.
+const DEFAULT_TOP_DAYS: i64 = 30;
+
+/// Query string accepted by `/stats/top.json`.
+#[derive(serde::Deserialize)]
+pub struct TopParams {
+    domain:      Option<String>,
+    page_prefix: Option<String>,
+    from:        Option<String>,
+    to:          Option<String>,
+    limit:       Option<String>,
+}
+
+/// First and last day of a period.
+type Days = (Date, Date);
+
+/// The period of `/stats/top.json`, starting at `from` or `DEFAULT_TOP_DAYS` before
+/// `to`, and the previous period, as `(from, to)` pairs. The previous period has the
+/// same length and ends the day before `from`.
+fn top_periods(from: Option<Date>, to: Date) -> Result<(Days, Days), String> {
+    let from = match from {
+        Some(from) => from,
+        None => to.checked_sub(Duration::days(DEFAULT_TOP_DAYS - 1))
+            .ok_or_else(|| format!("to ({to}) is too early"))?,
+    };
+    if from > to {
+        return Err(format!("from ({from}) is after to ({to})"));
+    }
+    let length = to - from + Duration::days(1);
+    let previous = from.checked_sub(length).zip(from.checked_sub(Duration::days(1)))
+        .ok_or_else(|| format!("from ({from}) is too early to compare with the previous period"))?;
+    Ok(((from, to), previous))
+}
+
+pub async fn top_pages(
+    State(state): State<AppState>,
+    access: Access,
+    Query(mut params): Query<TopParams>,
+) -> AppResult {
+    access.restrict(&mut params.domain)?;
+    let to = params.to.as_deref().map(|d| parse_date("to", d)).transpose()
+        .map_err(AppError::BadRequest)?.unwrap_or_else(|| OffsetDateTime::now_utc().date());
+    let from = params.from.as_deref().map(|d| parse_date("from", d)).transpose()
+        .map_err(AppError::BadRequest)?;
+    let ((from, to), (previous_from, previous_to)) = top_periods(from, to).map_err(AppError::BadRequest)?;
+    let limit = params.limit.as_deref().map(|l| parse_count("limit", l)).transpose()
+        .map_err(AppError::BadRequest)?.unwrap_or(DEFAULT_TOP_LIMIT).clamp(1, MAX_TOP_LIMIT);
+
+    let filter = |from, to| Filter {
+        domain: params.domain.clone(),
+        page: None,
+        page_prefix: params.page_prefix.clone(),
+        from: Some(from),
+        to: Some(to),
+        group_by: GroupBy::Day,
+        limit: Some(limit),
+        offset: 0,
+    };
+    let (current_where, mut params_vec) = filter(from, to).where_clause();
+    let (previous_where, previous_params) = filter(previous_from, previous_to).where_clause();
+    params_vec.extend(previous_params);
+
+    let query = format!(
+        "WITH current AS (
+             SELECT domain, page, SUM(view_count) AS view_count FROM pageviews {current_where} GROUP BY domain, page
+         ), previous AS (
+             SELECT domain, page, SUM(view_count) AS view_count FROM pageviews {previous_where} GROUP BY domain, page
+         )
+         SELECT current.domain, current.page, current.view_count, COALESCE(previous.view_count, 0)
+         FROM current LEFT JOIN previous USING (domain, page)
+         ORDER BY current.view_count DESC, current.domain, current.page
+         LIMIT ?"
+    );
+    params_vec.push(Box::new(limit as i64));
+
+    state.reads.run(move |db| {
+        let params_refs: Vec<&dyn ToSql> = params_vec.iter().map(|p| p.as_ref() as &dyn ToSql).collect();
+        let mut stmt = db.prepare(&query)?;
+        let rows = stmt.query_map(params_refs.as_slice(), |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, i64>(3)?
+            ))
+        })?;
+
+        let mut pages = Vec::new();
+        for (rank, row) in rows.enumerate() {
+            let (domain, page, view_count, previous_view_count) = row?;
+            // Percent change is undefined when the page had no views before
+            let percent_change = (previous_view_count > 0).then(|| {
+                let percent = (view_count - previous_view_count) as f64 * 100.0 / previous_view_count as f64;
+                (percent * 10.0).round() / 10.0
+            });
+
+            pages.push(serde_json::json!({
+                "rank": rank + 1,
+                "domain": domain,
+                "page": page,
+                "view_count": view_count,
+                "previous_view_count": previous_view_count,
+                "delta": view_count - previous_view_count,
+                "percent_change": percent_change
+            }));
+        }
+
+        let result = serde_json::json!({
+            "period": { "from": format_date(from), "to": format_date(to) },
+            "previous_period": { "from": format_date(previous_from), "to": format_date(previous_to) },
+            "pages": pages
+        });
+
+        Ok::<_, AppError>((
+            [("Content-Type", "application/json")],
+            serde_json::to_string_pretty(&result)?
+        ).into_response())
+    }).await
+}
+
+pub async fn top_referrers(
+    State(state): State<AppState>,
+    access: Access,
+    Query(params): Query<StatsParams>,
+) -> AppResult {
+    let mut filter = Filter::from_params(params).map_err(AppError::BadRequest)?;
+    access.restrict(&mut filter.domain)?;
+
+    let (where_clause, mut params_vec) = filter.where_clause();
+    let query = format!(
+        "SELECT referrer, SUM(view_count) AS view_count FROM referrers {where_clause}
+         GROUP BY referrer ORDER BY view_count DESC, referrer LIMIT ? OFFSET ?"
+    );
+    params_vec.push(Box::new(filter.page_limit() as i64));
+    params_vec.push(Box::new(filter.offset as i64));
+
+    state.reads.run(move |db| {
+        let params_refs: Vec<&dyn ToSql> = params_vec.iter().map(|p| p.as_ref() as &dyn ToSql).collect();
+        let mut stmt = db.prepare(&query)?;
+        let rows = stmt.query_map(params_refs.as_slice(), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+
+        let mut referrers = Vec::new();
+        for row in rows {
+            let (referrer, view_count) = row?;
+            referrers.push(serde_json::json!({
+                "referrer": referrer,
+                "view_count": view_count
+            }));
+        }
+
+        let result = serde_json::json!({ "referrers": referrers });
+
+        Ok::<_, AppError>((
+            [("Content-Type", "application/json")],
+            serde_json::to_string_pretty(&result)?
+        ).into_response())
+    }).await
+}
+
+pub async fn bots(
+    State(state): State<AppState>,
+    access: Access,
+    Query(params): Query<StatsParams>,
+) -> AppResult {
+    let mut filter = Filter::from_params(params).map_err(AppError::BadRequest)?;
+    access.restrict(&mut filter.domain)?;
+
+    let (where_clause, params_vec) = filter.where_clause();
+    let query = format!(
+        "SELECT bot, SUM(hit_count) AS hit_count FROM bot_hits {where_clause}
+         GROUP BY bot ORDER BY hit_count DESC, bot LIMIT ? OFFSET ?"
+    );
+    let (limit, offset) = (filter.page_limit() as i64, filter.offset as i64);
+
+    state.reads.run(move |db| {
+        let params_refs: Vec<&dyn ToSql> = params_vec.iter().map(|p| p.as_ref() as &dyn ToSql).collect();
+        let total_hits: i64 = db.query_row(
+            &format!("SELECT COALESCE(SUM(hit_count), 0) FROM bot_hits {where_clause}"),
+            params_refs.as_slice(),
+            |row| row.get(0),
+        )?;
+
+        let mut stmt = db.prepare(&query)?;
+        let rows = stmt.query_map(paged(&params_refs, &limit, &offset).as_slice(), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+
+        let mut bots = Vec::new();
+        for row in rows {
+            let (bot, hit_count) = row?;
+            bots.push(serde_json::json!({
+                "bot": bot,
+                "hit_count": hit_count
+            }));
+        }
+
+        let result = serde_json::json!({
+            "total_hits": total_hits,
+            "bots": bots
+        });
+
+        Ok::<_, AppError>((
+            [("Content-Type", "application/json")],
+            serde_json::to_string_pretty(&result)?
+        ).into_response())
+    }).await
+}
+
+/// Query string accepted by `/stats/events.json`.
+#[derive(serde::Deserialize)]
+pub struct EventParams {
+    /// Only count events with this name.
+    name:  Option<String>,
+    #[serde(flatten)]
+    stats: StatsParams,
+}
+
+/// `where_clause`, which may be empty, with `condition` added.
+fn and_where(where_clause: &str, condition: &str) -> String {
+    if where_clause.is_empty() { format!("WHERE {condition}") } else { format!("{where_clause} AND {condition}") }
+}
+
+/// `GET /stats/events.json`: events per name, paginated like `/stats.json`, with
+/// the breakdown by property value of the events on the current page.
+pub async fn events(
+    State(state): State<AppState>,
+    access: Access,
+    OriginalUri(uri): OriginalUri,
+    Query(params): Query<EventParams>,
+) -> AppResult {
+    let mut filter = Filter::from_params(params.stats).map_err(AppError::BadRequest)?;
+    access.restrict(&mut filter.domain)?;
+
+    let (mut where_clause, mut params_vec) = filter.where_clause();
+    if let Some(name) = params.name {
+        where_clause = and_where(&where_clause, "name = ?");
+        params_vec.push(Box::new(name));
+    }
+    let limit = filter.page_limit();
+    // One row more than requested tells whether there is a next page
+    let (page_limit, offset) = (limit as i64 + 1, filter.offset as i64);
+
+    state.reads.run(move |db| {
+        let params_refs: Vec<&dyn ToSql> = params_vec.iter().map(|p| p.as_ref() as &dyn ToSql).collect();
+        let (total_events, total_names): (i64, i64) = db.query_row(
+            &format!("SELECT COALESCE(SUM(event_count), 0), COUNT(DISTINCT name) FROM events {where_clause}"),
+            params_refs.as_slice(),
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        let mut stmt = db.prepare(&format!(
+            "SELECT name, SUM(event_count) AS event_count FROM events {where_clause}
+             GROUP BY name ORDER BY event_count DESC, name LIMIT ? OFFSET ?"
+        ))?;
+        let rows = stmt.query_map(paged(&params_refs, &page_limit, &offset).as_slice(), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        let mut counts = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let has_more = counts.len() as u64 > limit;
+        counts.truncate(limit as usize);
+        let next = has_more.then(|| next_link(&uri, filter.offset + limit));
+
+        // Breakdown by property value of the events on this page only, so that it
+        // is complete for each of them
+        let mut properties = Vec::new();
+        if !counts.is_empty() {
+            let names = vec!["?"; counts.len()].join(", ");
+            let mut stmt = db.prepare(&format!(
+                "SELECT name, key, value, SUM(event_count) AS event_count FROM event_props {}
+                 GROUP BY name, key, value ORDER BY name, key, event_count DESC, value",
+                and_where(&where_clause, &format!("name IN ({names})")),
+            ))?;
+            let names = counts.iter().map(|(name, _)| name as &dyn ToSql);
+            let params: Vec<&dyn ToSql> = params_refs.iter().copied().chain(names).collect();
+            let rows = stmt.query_map(params.as_slice(), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, i64>(3)?))
+            })?;
+            for row in rows {
+                let (name, key, value, event_count) = row?;
+                properties.push(serde_json::json!({
+                    "name": name,
+                    "key": key,
+                    "value": value,
+                    "event_count": event_count
+                }));
+            }
+        }
+
+        let events: Vec<_> = counts.into_iter()
+            .map(|(name, event_count)| serde_json::json!({ "name": name, "event_count": event_count }))
+            .collect();
+
+        let pagination = serde_json::json!({
+            "limit": limit,
+            "offset": filter.offset,
+            "next": next
+        });
+
+        let result = serde_json::json!({
+            "total_events": total_events,
+            "total_names": total_names,
+            "pagination": pagination,
+            "events": events,
+            "properties": properties
+        });
+
+        Ok::<_, AppError>((
+            [("Content-Type", "application/json")],
+            serde_json::to_string_pretty(&result)?
+        ).into_response())
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_count_rejects_values_sqlite_cannot_take() {
+        assert_eq!(parse_count("offset", "0"), Ok(0));
+        assert_eq!(parse_count("offset", &i64::MAX.to_string()), Ok(i64::MAX as u64));
+        assert!(parse_count("offset", "9223372036854775808").is_err());
+        assert!(parse_count("offset", "18446744073709551615").is_err());
+        assert!(parse_count("offset", "-1").is_err());
+        assert!(parse_count("limit", "ten").is_err());
+    }
+
+    #[test]
+    fn top_periods_compare_with_previous_period() {
+        let date = |d| parse_date("date", d).unwrap();
+        assert_eq!(
+            top_periods(Some(date("2024-05-08")), date("2024-05-14")),
+            Ok(((date("2024-05-08"), date("2024-05-14")), (date("2024-05-01"), date("2024-05-07")))),
+        );
+        assert_eq!(top_periods(None, date("2024-05-30")).map(|(current, _)| current.0), Ok(date("2024-05-01")));
+        assert!(top_periods(Some(date("2024-05-15")), date("2024-05-14")).is_err());
+    }
+
+    #[test]
+    fn top_periods_reject_extreme_dates() {
+        let date = |d| parse_date("date", d).unwrap();
+        assert!(top_periods(Some(date("0000-01-01")), date("9999-12-31")).is_err());
+        assert!(top_periods(Some(date("-9999-01-01")), date("-9999-01-01")).is_err());
+        assert!(top_periods(None, date("-9999-01-01")).is_err());
+        assert!(top_periods(Some(date("-9999-01-02")), date("-9999-01-02")).is_ok());
+    }
+
+    #[test]
+    fn and_where_adds_conditions() {
+        assert_eq!(and_where("", "name = ?"), "WHERE name = ?");
+        assert_eq!(and_where("WHERE domain = ?", "name = ?"), "WHERE domain = ? AND name = ?");
+    }
+
+    #[test]
+    fn next_link_replaces_offset_and_keeps_other_parameters() {
+        let uri: Uri = "/stats.json?domain=example.com&offset=10&limit=10".parse().unwrap();
+        assert_eq!(next_link(&uri, 20), "/stats.json?domain=example.com&limit=10&offset=20");
+    }
+
+    #[test]
+    fn next_link_adds_offset_without_query() {
+        let uri: Uri = "/share/pps_x/stats.json".parse().unwrap();
+        assert_eq!(next_link(&uri, 1000), "/share/pps_x/stats.json?offset=1000");
+    }
+}
@@ -0,0 +1,194 @@
+use axum::http::{HeaderMap, HeaderName};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+};
+use time::Date;
+
+/// Headers set by reverse proxies to pass on the client's address, in order of preference.
+const CLIENT_IP_HEADERS: &[&str] = &["fly-client-ip", "x-real-ip", "x-forwarded-for"];
+
+/// Most hashes kept per day, about 16 MB. Pages are client-supplied, so without a
+/// bound junk page values could grow memory without limit.
+const MAX_HASHES: usize = 1_000_000;
+
+/// Estimates unique visitors per day without storing anything that identifies them.
+///
+/// Each visitor is reduced to a hash of their IP address and User-Agent, keyed with a
+/// random salt that only lives in memory and is replaced at the start of every day.
+/// The hashes of the current day are kept in memory to tell new visitors from returning
+/// ones; only the resulting counts are written to the database. Hashes from different
+/// days cannot be linked, and after a restart earlier hashes cannot be recomputed.
+///
+/// Once a quarter of the day's capacity is left, only visitors to a site are
+/// tracked, no longer those to each page; once it is full, no new visitors are
+/// counted until the next day.
+pub struct VisitorTracker {
+    day:        Mutex<Day>,
+    max_hashes: usize,
+}
+
+struct Day {
+    date: Date,
+    salt: [u8; 32],
+    seen: HashSet<u64>,
+    /// Whether running out of capacity has been logged.
+    full: bool,
+}
+
+/// Whether a hit is the visitor's first of the day.
+pub struct NewVisitor {
+    pub to_page: bool,
+    pub to_site: bool,
+}
+
+impl VisitorTracker {
+    pub fn new(today: Date) -> Result<VisitorTracker, String> {
+        VisitorTracker::with_capacity(today, MAX_HASHES)
+    }
+
+    fn with_capacity(today: Date, max_hashes: usize) -> Result<VisitorTracker, String> {
+        let mut salt = [0u8; 32];
+        getrandom::fill(&mut salt).map_err(|e| format!("cannot generate visitor salt: {e}"))?;
+        let day = Day { date: today, salt, seen: HashSet::new(), full: false };
+        Ok(VisitorTracker { day: Mutex::new(day), max_hashes })
+    }
+
+    /// Records a hit on `date`. Hits dated before the current day, taken just before
+    /// midnight, are not counted as new, as that day's hashes are gone.
+    pub fn record(&self, date: Date, domain: &str, page: &str, ip: IpAddr, user_agent: &str) -> NewVisitor {
+        let mut day = self.day.lock().unwrap_or_else(|e| e.into_inner());
+        if date > day.date {
+            let salt = day.next_salt();
+            *day = Day { date, salt, seen: HashSet::new(), full: false };
+        } else if date < day.date {
+            return NewVisitor { to_page: false, to_site: false };
+        }
+
+        let ip = ip.to_string();
+        let site_hash = day.hash(&[domain, &ip, user_agent]);
+        let page_hash = day.hash(&[domain, page, &ip, user_agent]);
+        NewVisitor {
+            to_site: day.insert(site_hash, self.max_hashes),
+            to_page: day.insert(page_hash, self.max_hashes / 4 * 3),
+        }
+    }
+}
+
+impl Day {
+    /// Keyed hash of `parts`, each prefixed with its length so that no two
+    /// different lists of parts are hashed alike.
+    fn hash(&self, parts: &[&str]) -> u64 {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt);
+        for part in parts {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        let digest = hasher.finalize();
+        u64::from_le_bytes(digest[..8].try_into().expect("digest is 32 bytes"))
+    }
+
+    /// Adds `hash` unless `limit` hashes are kept already, and returns whether it
+    /// was new. A visitor that cannot be added is taken as returning.
+    fn insert(&mut self, hash: u64, limit: usize) -> bool {
+        if self.seen.len() >= limit && !self.seen.contains(&hash) {
+            if !self.full {
+                tracing::warn!(hashes = self.seen.len(), "too many visitors today, no longer counting some as new");
+                self.full = true;
+            }
+            return false;
+        }
+        self.seen.insert(hash)
+    }
+
+    /// A fresh random salt for the next day. Should the system's random source
+    /// fail, derives one from the current salt, which is just as secret.
+    fn next_salt(&self) -> [u8; 32] {
+        let mut salt = [0u8; 32];
+        if let Err(e) = getrandom::fill(&mut salt) {
+            tracing::error!(error = %e, "cannot generate visitor salt, deriving it from the previous one");
+            salt = Sha256::new().chain_update(self.salt).chain_update(self.date.to_string()).finalize().into();
+        }
+        salt
+    }
+}
+
+/// The client's address, as reported by a reverse proxy in front of the service or
+/// otherwise the address of the connection.
+pub fn client_ip(headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+    CLIENT_IP_HEADERS.iter()
+        .filter_map(|name| headers.get(HeaderName::from_static(name)))
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(|value| value.split(',').next())
+        .find_map(|ip| ip.trim().parse().ok())
+        .unwrap_or(peer.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::date;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+
+    #[test]
+    fn returning_visitors_are_not_new() {
+        let tracker = VisitorTracker::new(date!(2025 - 01 - 01)).unwrap();
+        let first = tracker.record(date!(2025 - 01 - 01), "example.com", "/", IP, "Firefox");
+        assert!(first.to_site && first.to_page);
+        let again = tracker.record(date!(2025 - 01 - 01), "example.com", "/", IP, "Firefox");
+        assert!(!again.to_site && !again.to_page);
+        let other_page = tracker.record(date!(2025 - 01 - 01), "example.com", "/about", IP, "Firefox");
+        assert!(!other_page.to_site && other_page.to_page);
+        let other_browser = tracker.record(date!(2025 - 01 - 01), "example.com", "/", IP, "Chrome");
+        assert!(other_browser.to_site && other_browser.to_page);
+    }
+
+    #[test]
+    fn visitors_are_new_again_the_next_day() {
+        let tracker = VisitorTracker::new(date!(2025 - 01 - 01)).unwrap();
+        tracker.record(date!(2025 - 01 - 01), "example.com", "/", IP, "Firefox");
+        let next_day = tracker.record(date!(2025 - 01 - 02), "example.com", "/", IP, "Firefox");
+        assert!(next_day.to_site && next_day.to_page);
+    }
+
+    #[test]
+    fn late_hits_of_the_previous_day_keep_the_current_day() {
+        let tracker = VisitorTracker::new(date!(2025 - 01 - 01)).unwrap();
+        assert!(tracker.record(date!(2025 - 01 - 02), "example.com", "/", IP, "Firefox").to_site);
+        let late = tracker.record(date!(2025 - 01 - 01), "example.com", "/", IP, "Chrome");
+        assert!(!late.to_site && !late.to_page);
+        let again = tracker.record(date!(2025 - 01 - 02), "example.com", "/", IP, "Firefox");
+        assert!(!again.to_site && !again.to_page);
+    }
+
+    #[test]
+    fn page_hashes_stop_before_site_hashes_when_full() {
+        let tracker = VisitorTracker::with_capacity(date!(2025 - 01 - 01), 8).unwrap();
+        for page in 0..10 {
+            tracker.record(date!(2025 - 01 - 01), "example.com", &format!("/{page}"), IP, "Firefox");
+        }
+        // Page hashes stopped at 6 of 8, leaving room for two more visitors to the site
+        let visitor = tracker.record(date!(2025 - 01 - 01), "example.com", "/", IP, "Chrome");
+        assert!(visitor.to_site && !visitor.to_page);
+        let visitor = tracker.record(date!(2025 - 01 - 01), "example.com", "/", IP, "Safari");
+        assert!(visitor.to_site && !visitor.to_page);
+        let visitor = tracker.record(date!(2025 - 01 - 01), "example.com", "/", IP, "Edge");
+        assert!(!visitor.to_site && !visitor.to_page);
+        assert_eq!(tracker.day.lock().unwrap().seen.len(), 8);
+    }
+
+    #[test]
+    fn client_ip_prefers_proxy_headers() {
+        let peer: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        assert_eq!(client_ip(&headers, peer), peer.ip());
+        headers.insert("x-forwarded-for", "192.0.2.1, 10.0.0.2".parse().unwrap());
+        assert_eq!(client_ip(&headers, peer), IP);
+        headers.insert("fly-client-ip", "2001:db8::1".parse().unwrap());
+        assert_eq!(client_ip(&headers, peer), "2001:db8::1".parse::<IpAddr>().unwrap());
+    }
+}
@@ -0,0 +1,380 @@
+use rusqlite::Connection;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+use crate::metrics::Metrics;
+
+/// Most rows of counts kept in memory. While the database cannot be written,
+/// hits that would add to a larger backlog are dropped, as are failed batches
+/// that would grow it beyond this.
+const MAX_PENDING_ROWS: usize = 100_000;
+/// Longest wait between attempts to write after failures.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Buffers counts in memory and writes them to the database in batches.
+///
+/// Counting a hit only updates an in-memory aggregate, so requests never wait for
+/// the database. A background task, `run`, flushes the aggregate in one transaction
+/// every flush interval, or as soon as `flush_hits` hits have been buffered. Counts
+/// that fail to be written are kept and retried with the next flush, after a delay
+/// that doubles with every failure, up to `MAX_PENDING_ROWS` rows.
+pub struct Writer {
+    db:         Arc<Mutex<Connection>>,
+    metrics:    Arc<Metrics>,
+    pending:    Mutex<Pending>,
+    flush_hits: usize,
+    max_rows:   usize,
+    wake:       Notify,
+    stop:       Notify,
+    stopping:   AtomicBool,
+    /// Why the last flush failed, cleared by the next one that succeeds.
+    last_error: Mutex<Option<String>>,
+}
+
+/// Counts not yet written to the database, keyed like the rows they add to.
+#[derive(Default)]
+pub struct Pending {
+    hits:          usize,
+    pageviews:     HashMap<(String, String, String), i64>,
+    referrers:     HashMap<(String, String, String, String), i64>,
+    bot_hits:      HashMap<(String, String, String, String), i64>,
+    unregistered:  HashMap<(String, String), i64>,
+    visitors:      HashMap<(String, String, String), i64>,
+    site_visitors: HashMap<(String, String), i64>,
+    events:        HashMap<(String, String, String, String), i64>,
+    event_props:   HashMap<(String, String, String, String, String, String), i64>,
+}
+
+impl Pending {
+    pub fn page_view(&mut self, domain: &str, page: &str, date: &str) {
+        increment(&mut self.pageviews, (domain.into(), page.into(), date.into()), 1);
+    }
+
+    pub fn referrer(&mut self, domain: &str, page: &str, date: &str, referrer: &str) {
+        increment(&mut self.referrers, (domain.into(), page.into(), date.into(), referrer.into()), 1);
+    }
+
+    pub fn bot_hit(&mut self, domain: &str, page: &str, date: &str, bot: &str) {
+        increment(&mut self.bot_hits, (domain.into(), page.into(), date.into(), bot.into()), 1);
+    }
+
+    pub fn unregistered_hit(&mut self, domain: &str, date: &str) {
+        increment(&mut self.unregistered, (domain.into(), date.into()), 1);
+    }
+
+    pub fn visitor(&mut self, domain: &str, page: &str, date: &str) {
+        increment(&mut self.visitors, (domain.into(), page.into(), date.into()), 1);
+    }
+
+    pub fn site_visitor(&mut self, domain: &str, date: &str) {
+        increment(&mut self.site_visitors, (domain.into(), date.into()), 1);
+    }
+
+    pub fn event(&mut self, domain: &str, page: &str, date: &str, name: &str) {
+        increment(&mut self.events, (domain.into(), page.into(), date.into(), name.into()), 1);
+    }
+
+    pub fn event_prop(&mut self, domain: &str, page: &str, date: &str, name: &str, key: &str, value: &str) {
+        let key = (domain.into(), page.into(), date.into(), name.into(), key.into(), value.into());
+        increment(&mut self.event_props, key, 1);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.hits == 0
+    }
+
+    /// Number of rows the counts add to.
+    fn rows(&self) -> usize {
+        self.pageviews.len() + self.referrers.len() + self.bot_hits.len() + self.unregistered.len()
+            + self.visitors.len() + self.site_visitors.len() + self.events.len() + self.event_props.len()
+    }
+
+    /// Adds the counts of `other`, e.g. of a batch that could not be written.
+    fn merge(&mut self, other: Pending) {
+        self.hits += other.hits;
+        for (key, n) in other.pageviews { increment(&mut self.pageviews, key, n) }
+        for (key, n) in other.referrers { increment(&mut self.referrers, key, n) }
+        for (key, n) in other.bot_hits { increment(&mut self.bot_hits, key, n) }
+        for (key, n) in other.unregistered { increment(&mut self.unregistered, key, n) }
+        for (key, n) in other.visitors { increment(&mut self.visitors, key, n) }
+        for (key, n) in other.site_visitors { increment(&mut self.site_visitors, key, n) }
+        for (key, n) in other.events { increment(&mut self.events, key, n) }
+        for (key, n) in other.event_props { increment(&mut self.event_props, key, n) }
+    }
+}
+
+fn increment<K: Hash + Eq>(counts: &mut HashMap<K, i64>, key: K, n: i64) {
+    *counts.entry(key).or_insert(0) += n;
+}
+
+impl Writer {
+    pub fn new(db: Arc<Mutex<Connection>>, metrics: Arc<Metrics>, flush_hits: usize) -> Writer {
+        Writer {
+            db,
+            metrics,
+            pending: Mutex::new(Pending::default()),
+            flush_hits,
+            max_rows: MAX_PENDING_ROWS,
+            wake: Notify::new(),
+            stop: Notify::new(),
+            stopping: AtomicBool::new(false),
+            last_error: Mutex::new(None),
+        }
+    }
+
+    /// Buffers the counts of one hit, added by `f`, unless the backlog is full.
+    pub fn record(&self, f: impl FnOnce(&mut Pending)) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        if pending.rows() >= self.max_rows {
+            self.metrics.drop_hits(1);
+            return;
+        }
+        f(&mut pending);
+        pending.hits += 1;
+        if pending.hits >= self.flush_hits {
+            self.wake.notify_one();
+        }
+    }
+
+    /// Views of `page` on or after `since` that have not been written yet.
+    pub fn pending_views(&self, domain: &str, page: &str, since: &str) -> i64 {
+        let pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.pageviews.iter()
+            .filter(|((d, p, date), _)| d == domain && p == page && date.as_str() >= since)
+            .map(|(_, n)| n)
+            .sum()
+    }
+
+    /// Number of hits buffered since the last flush.
+    pub fn buffered_hits(&self) -> usize {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).hits
+    }
+
+    /// The error of the last flush, if it failed.
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Flushes buffered counts every `interval`, or sooner when enough hits are
+    /// buffered, until `stop` is called. Flushes once more before returning.
+    ///
+    /// After a failed flush, waits longer before each new attempt, and only then,
+    /// so that a full buffer does not retry in a loop.
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        let mut failures = 0;
+        while !self.stopping.load(Ordering::Acquire) {
+            tokio::select! {
+                _ = tokio::time::sleep(backoff(interval, failures)) => {}
+                _ = self.wake.notified(), if failures == 0 => {}
+                _ = self.stop.notified() => {}
+            }
+            failures = if self.flush().await { 0 } else { failures + 1 };
+        }
+        self.flush().await;
+    }
+
+    /// Makes `run` write what is buffered and return.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::Release);
+        self.stop.notify_one();
+    }
+
+    /// Writes the buffered counts, and returns whether that succeeded.
+    async fn flush(self: &Arc<Self>) -> bool {
+        let writer = self.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            // Take the batch while holding the database lock, so readers that also
+            // add `pending_views` never miss a batch that is being written
+            let mut db = writer.db.lock().unwrap_or_else(|e| e.into_inner());
+            let batch = mem::take(&mut *writer.pending.lock().unwrap_or_else(|e| e.into_inner()));
+            if batch.is_empty() {
+                return Ok(());
+            }
+            let started = Instant::now();
+            let result = write(&mut db, &batch);
+            writer.metrics.write(started.elapsed(), result.is_ok());
+            *writer.last_error.lock().unwrap_or_else(|e| e.into_inner()) = result.as_ref().err().map(|e| e.to_string());
+            result.map_err(|e| {
+                let mut pending = writer.pending.lock().unwrap_or_else(|e| e.into_inner());
+                if pending.rows() + batch.rows() > writer.max_rows {
+                    tracing::error!(error = %e, hits = batch.hits, "cannot write buffered hits, too many to keep, dropping them");
+                    writer.metrics.drop_hits(batch.hits);
+                } else {
+                    tracing::error!(error = %e, hits = batch.hits, "cannot write buffered hits, will retry");
+                    pending.merge(batch);
+                }
+            })
+        }).await;
+
+        match result {
+            Ok(result) => result.is_ok(),
+            Err(e) => {
+                tracing::error!(error = %e, "buffered hits lost");
+                false
+            }
+        }
+    }
+}
+
+/// How long to wait before the next flush, after `failures` failed ones in a row.
+fn backoff(interval: Duration, failures: u32) -> Duration {
+    (interval * 2u32.pow(failures.min(16))).min(MAX_BACKOFF.max(interval))
+}
+
+/// Adds the counts of `batch` to the database in one transaction.
+fn write(db: &mut Connection, batch: &Pending) -> rusqlite::Result<()> {
+    let tx = db.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO pageviews (domain, page, date, view_count) VALUES (?, ?, ?, ?)
+             ON CONFLICT (domain, page, date) DO UPDATE SET view_count = view_count + excluded.view_count",
+        )?;
+        for ((domain, page, date), n) in &batch.pageviews {
+            stmt.execute((domain, page, date, n))?;
+        }
+
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO referrers (domain, page, date, referrer, view_count) VALUES (?, ?, ?, ?, ?)
+             ON CONFLICT (domain, page, date, referrer) DO UPDATE SET view_count = view_count + excluded.view_count",
+        )?;
+        for ((domain, page, date, referrer), n) in &batch.referrers {
+            stmt.execute((domain, page, date, referrer, n))?;
+        }
+
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO bot_hits (domain, page, date, bot, hit_count) VALUES (?, ?, ?, ?, ?)
+             ON CONFLICT (domain, page, date, bot) DO UPDATE SET hit_count = hit_count + excluded.hit_count",
+        )?;
+        for ((domain, page, date, bot), n) in &batch.bot_hits {
+            stmt.execute((domain, page, date, bot, n))?;
+        }
+
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO unregistered_hits (domain, date, hit_count) VALUES (?, ?, ?)
+             ON CONFLICT (domain, date) DO UPDATE SET hit_count = hit_count + excluded.hit_count",
+        )?;
+        for ((domain, date), n) in &batch.unregistered {
+            stmt.execute((domain, date, n))?;
+        }
+
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO visitors (domain, page, date, visitor_count) VALUES (?, ?, ?, ?)
+             ON CONFLICT (domain, page, date) DO UPDATE SET visitor_count = visitor_count + excluded.visitor_count",
+        )?;
+        for ((domain, page, date), n) in &batch.visitors {
+            stmt.execute((domain, page, date, n))?;
+        }
+
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO site_visitors (domain, date, visitor_count) VALUES (?, ?, ?)
+             ON CONFLICT (domain, date) DO UPDATE SET visitor_count = visitor_count + excluded.visitor_count",
+        )?;
+        for ((domain, date), n) in &batch.site_visitors {
+            stmt.execute((domain, date, n))?;
+        }
+
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO events (domain, page, date, name, event_count) VALUES (?, ?, ?, ?, ?)
+             ON CONFLICT (domain, page, date, name) DO UPDATE SET event_count = event_count + excluded.event_count",
+        )?;
+        for ((domain, page, date, name), n) in &batch.events {
+            stmt.execute((domain, page, date, name, n))?;
+        }
+
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO event_props (domain, page, date, name, key, value, event_count) VALUES (?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT (domain, page, date, name, key, value) DO UPDATE SET event_count = event_count + excluded.event_count",
+        )?;
+        for ((domain, page, date, name, key, value), n) in &batch.event_props {
+            stmt.execute((domain, page, date, name, key, value, n))?;
+        }
+    }
+    tx.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writer(db: Connection) -> Arc<Writer> {
+        Arc::new(Writer::new(Arc::new(Mutex::new(db)), Arc::new(Metrics::default()), 100))
+    }
+
+    fn view(writer: &Writer, page: &str) {
+        writer.record(|pending| pending.page_view("example.com", page, "2024-05-01"));
+    }
+
+    #[test]
+    fn merge_adds_counts() {
+        let mut a = Pending::default();
+        a.page_view("example.com", "/", "2024-05-01");
+        a.hits = 1;
+        let mut b = Pending::default();
+        b.page_view("example.com", "/", "2024-05-01");
+        b.page_view("example.com", "/about", "2024-05-01");
+        b.event("example.com", "/", "2024-05-01", "signup");
+        b.hits = 2;
+
+        a.merge(b);
+        assert_eq!(a.hits, 3);
+        assert_eq!(a.rows(), 3);
+        assert_eq!(a.pageviews[&("example.com".into(), "/".into(), "2024-05-01".into())], 2);
+        assert_eq!(a.events.len(), 1);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_limit() {
+        let interval = Duration::from_secs(1);
+        assert_eq!(backoff(interval, 0), interval);
+        assert_eq!(backoff(interval, 3), Duration::from_secs(8));
+        assert_eq!(backoff(interval, 100), MAX_BACKOFF);
+        assert_eq!(backoff(Duration::from_secs(120), 2), Duration::from_secs(120));
+    }
+
+    #[tokio::test]
+    async fn failed_flush_is_retried() {
+        // No tables yet, so writing fails
+        let writer = writer(Connection::open_in_memory().unwrap());
+        view(&writer, "/");
+        view(&writer, "/");
+
+        assert!(!writer.flush().await);
+        assert!(writer.last_error().is_some());
+        assert_eq!(writer.buffered_hits(), 2);
+        assert_eq!(writer.pending_views("example.com", "/", "2024-05-01"), 2);
+
+        crate::migrations::migrate(&mut writer.db.lock().unwrap()).unwrap();
+        assert!(writer.flush().await);
+        assert!(writer.last_error().is_none());
+        assert_eq!(writer.buffered_hits(), 0);
+        let views: i64 = writer.db.lock().unwrap()
+            .query_row("SELECT view_count FROM pageviews WHERE page = '/'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(views, 2);
+    }
+
+    #[tokio::test]
+    async fn backlog_is_capped() {
+        let mut writer = Writer::new(Arc::new(Mutex::new(Connection::open_in_memory().unwrap())), Arc::new(Metrics::default()), 100);
+        writer.max_rows = 2;
+        view(&writer, "/a");
+        view(&writer, "/b");
+        view(&writer, "/c");
+        assert_eq!(writer.buffered_hits(), 2);
+
+        // A failed batch that does not fit any more is dropped rather than kept
+        writer.max_rows = 1;
+        let writer = Arc::new(writer);
+        assert!(!writer.flush().await);
+        assert_eq!(writer.buffered_hits(), 0);
+    }
+}